@@ -4,6 +4,7 @@ wit_bindgen_guest_rust::generate!({path:"./wit/filesystem.wit"});
 pub(crate) mod imports {
     wasmtime::component::bindgen!({
         path: "./wit/filesystem.wit",
+        async: true,
     });
     
     pub use filesystem::add_to_linker;
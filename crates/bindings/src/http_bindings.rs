@@ -1,26 +1,84 @@
 wit_bindgen_guest_rust::generate!({path:"./wit/http_service.wit", macro_export});
+
+pub(crate) mod body {
+    pub use super::http_body::*;
+
+    /// Size of the chunks requested from the host by [`read_to_end`].
+    const READ_CHUNK_SIZE: u32 = 64 * 1024;
+
+    /// Read an incoming body to completion.
+    pub fn read_to_end(body: IncomingBody) -> Result<Vec<u8>, String> {
+        let mut contents = Vec::new();
+        while let Some(chunk) = read(body, READ_CHUNK_SIZE)? {
+            contents.extend_from_slice(&chunk);
+        }
+        Ok(contents)
+    }
+
+    /// Create an outgoing body containing `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<OutgoingBody, String> {
+        let body = new_outgoing();
+        write(body, bytes)?;
+        Ok(body)
+    }
+
+    /// Send the response head right away, so that whatever is written to
+    /// `body` from then on reaches the client without waiting for the handler
+    /// to return.
+    pub fn stream(
+        status: u16,
+        headers: &[super::http_component::Header],
+        body: OutgoingBody,
+    ) -> Result<(), String> {
+        let headers: Vec<(&[u8], &[u8])> = headers
+            .iter()
+            .map(|header| (header.key.as_slice(), header.value.as_slice()))
+            .collect();
+        send_response(status, &headers, body)
+    }
+}
+
+impl http_component::Method {
+    /// The method as it appears on the request line, e.g. `GET` or `PROPFIND`.
+    pub fn as_str(&self) -> &str {
+        use http_component::Method;
+        match self {
+            Method::Options => "OPTIONS",
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method,
+        }
+    }
+}
+
 #[cfg(feature="import")]
 pub(crate) mod imports {
     wasmtime::component::bindgen!({
         path: "./wit/http_service.wit",
+        async: true,
     });
 
     pub use http_import::add_to_linker;
 
-    impl TryFrom<hyper::Method> for Method {
-        type Error = String;
-        fn try_from(method: hyper::Method) -> Result<Self, Self::Error> {
-            match method {
-                hyper::Method::OPTIONS => Ok(Method::Options),
-                hyper::Method::GET => Ok(Method::Get),
-                hyper::Method::POST => Ok(Method::Post),
-                hyper::Method::PUT => Ok(Method::Put),
-                hyper::Method::DELETE => Ok(Method::Delete),
-                hyper::Method::HEAD => Ok(Method::Head),
-                hyper::Method::TRACE => Ok(Method::Trace),
-                hyper::Method::CONNECT => Ok(Method::Connect),
-                hyper::Method::PATCH => Ok(Method::Patch),
-                _ => Err("Unknown method".to_string()),
+    impl<'a> From<&'a hyper::Method> for Method<'a> {
+        fn from(method: &'a hyper::Method) -> Self {
+            match *method {
+                hyper::Method::OPTIONS => Method::Options,
+                hyper::Method::GET => Method::Get,
+                hyper::Method::POST => Method::Post,
+                hyper::Method::PUT => Method::Put,
+                hyper::Method::DELETE => Method::Delete,
+                hyper::Method::HEAD => Method::Head,
+                hyper::Method::TRACE => Method::Trace,
+                hyper::Method::CONNECT => Method::Connect,
+                hyper::Method::PATCH => Method::Patch,
+                _ => Method::Other(method.as_str()),
             }
         }
     }
@@ -55,7 +113,7 @@ pub(crate) mod imports {
     //     type Error = String;
 
     //     fn try_into(self) -> Result<Request<'a>, Self::Error> {
-    //         let method = Method::try_from(self.method().clone())?;
+    //         let method = Method::from(self.method());
     //         let version = Version::try_from(self.version())?;
     //         let uri: &'a str = self.uri().path();
     //         let headers: Vec<HeaderParam<'a>> = self
@@ -80,33 +138,16 @@ pub(crate) mod imports {
     // }
 
     impl Response {
-        pub fn into_hyper_response<T>(self) -> hyper::Response<T>
-        where
-            T: Default + From<Vec<u8>>,
-        {
-            let mut res = hyper::Response::builder()
-                .status(self.status)
-                .version(self.version.into());
-            for header in self.headers {
-                res = res.header(header.key, header.value);
-            }
-            res.body(T::from(self.body)).unwrap()
+        pub fn into_hyper_response<T>(self, body: T) -> hyper::Response<T> {
+            self.response_builder().body(body).unwrap()
         }
 
-        pub fn try_into_hyper_response<T>(self) -> Result<hyper::Response<T>, T::Error>
-        where
-            T: Default + TryFrom<Vec<u8>>,
-        {
-            let mut res = hyper::Response::builder()
-                .status(self.status)
-                .version(self.version.into());
-            for header in self.headers {
-                res = res.header(header.key, header.value);
-            }
-            Ok(res.body(T::try_from(self.body)?).unwrap())
+        /// Like [`Response::into_hyper_response`], but reports an invalid
+        /// status or header from the guest instead of panicking.
+        pub fn try_into_hyper_response<T>(self, body: T) -> hyper::http::Result<hyper::Response<T>> {
+            self.response_builder().body(body)
         }
 
-
         pub fn response_builder(self) -> hyper::http::response::Builder
         {
             let mut res = hyper::Response::builder()
@@ -118,20 +159,4 @@ pub(crate) mod imports {
             res
         }
     }
-
-    impl<T> TryInto<hyper::Response<T>> for Response
-    where
-        T: Default + From<Vec<u8>>,
-    {
-        type Error = String;
-        fn try_into(self) -> Result<hyper::Response<T>, Self::Error> {
-            let mut res = hyper::Response::builder()
-                .status(self.status)
-                .version(self.version.into());
-            for header in self.headers {
-                res = res.header(header.key, header.value);
-            }
-            Ok(res.body(T::from(self.body)).unwrap())
-        }
-    }
 }
@@ -3,6 +3,9 @@ mod filesystem_bindings;
 
 pub mod http {
     pub use crate::http_bindings::http_component::*;
+    pub mod body {
+        pub use crate::http_bindings::body::*;
+    }
     #[cfg(feature = "import")]
     pub mod imports {
         pub use crate::http_bindings::imports::*;
@@ -1,26 +1,64 @@
 use apogee_sdk::entrypoint;
 use apogee_sdk::filesystem;
-use apogee_sdk::http::{Request, Response};
+use apogee_sdk::http::{body, Header, Request, Response};
 
 #[entrypoint(http)]
 pub fn handle_http_request(req: Request) -> Result<Response, String> {
+    if req.headers.iter().any(|h| h.key == b"x-wit-throw-error") {
+        return Err("Error - x-wit-throw-error header found".to_string());
+    }
+
+    // Serve any other file in the data directory as-is
+    let path = req.context.path.as_str();
+    if path != "/" && !path.ends_with("index.html") {
+        let contents = filesystem::read_file(path)?;
+        return Ok(Response {
+            status: 200,
+            version: req.version,
+            headers: vec![
+                header("x-wit-test", "true"),
+                header("Content-Type", content_type(path)),
+            ],
+            body: body::from_bytes(&contents)?,
+        });
+    }
+
     // Read the index.html file from the filesystem
     let index_html = filesystem::read_file("index.html")?;
     // format {route} into the index.html file
     let index_html = std::str::from_utf8(&index_html)
         .unwrap()
         .replace("{route}", &req.uri);
-    if req.headers.iter().any(|h| h.key == b"x-wit-throw-error") {
-        Err("Error - x-wit-throw-error header found".to_string())
-    } else {
-        Ok(Response {
-            status: 200,
-            version: req.version,
-            headers: vec![apogee_sdk::http::Header {
-                key: b"x-wit-test".to_vec(),
-                value: b"true".to_vec(),
-            }],
-            body: index_html.into_bytes(),
-        })
+    Ok(Response {
+        status: 200,
+        version: req.version,
+        headers: vec![
+            header("x-wit-test", "true"),
+            header("Content-Type", "text/html; charset=utf-8"),
+        ],
+        body: body::from_bytes(index_html.as_bytes())?,
+    })
+}
+
+fn header(key: &str, value: &str) -> Header {
+    Header {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or_default() {
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
     }
 }
@@ -0,0 +1,89 @@
+//! Instantiations per second of the sample component, once resolving its
+//! imports with a fresh `Linker` every time and once from an `InstancePre`
+//! created up front, as services do since they link at load time:
+//!
+//! ```sh
+//! cargo bench -p apogee-host --bench instantiate
+//! ```
+
+// The host's modules are compiled in as they are, and the benchmark only uses
+// part of them
+#![allow(dead_code)]
+
+#[path = "../src/body.rs"]
+mod body;
+#[path = "../src/ctx.rs"]
+mod ctx;
+#[path = "../src/filesystem.rs"]
+mod filesystem;
+#[path = "../src/limits.rs"]
+mod limits;
+#[path = "../src/pool.rs"]
+mod pool;
+#[path = "../src/runtime.rs"]
+mod runtime;
+
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use apogee_sdk::http::imports::HttpComponent;
+use wasmtime::component::Component;
+use wasmtime::Store;
+
+use ctx::RequestCtx;
+use limits::ServiceLimits;
+use runtime::Runtime;
+
+/// How long each approach is measured for.
+const DURATION: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() {
+    let runtime = Runtime::new(None).unwrap();
+    let wasm = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../apogee/guest");
+    let wasm = wasm.join("guest.component.wasm");
+    let component = Component::from_file(&runtime.engine, wasm).unwrap();
+    let instance_pre = runtime.linker.instantiate_pre(&component).unwrap();
+    let (runtime, component, instance_pre) = (&runtime, &component, &instance_pre);
+
+    let rate = measure(move || async move {
+        let linker = runtime::init_linker(&runtime.engine).unwrap();
+        let mut store = new_store(runtime);
+        let instance = linker.instantiate_async(&mut store, component).await.unwrap();
+        HttpComponent::new(&mut store, &instance).unwrap();
+    })
+    .await;
+    println!("Linker per instantiation: {rate:.0} instantiations/s");
+
+    let rate = measure(move || async move {
+        let mut store = new_store(runtime);
+        let instance = instance_pre.instantiate_async(&mut store).await.unwrap();
+        HttpComponent::new(&mut store, &instance).unwrap();
+    })
+    .await;
+    println!("InstancePre: {rate:.0} instantiations/s");
+}
+
+fn new_store(runtime: &Runtime) -> Store<RequestCtx> {
+    let mut store = Store::new(&runtime.engine, RequestCtx::new());
+    limits::configure_store(&mut store);
+    limits::apply(&mut store, &ServiceLimits::default()).unwrap();
+    store
+}
+
+/// Run `instantiate` repeatedly for `DURATION`, returning how many times per
+/// second it completed.
+async fn measure<F, Fut>(mut instantiate: F) -> f64
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let started = Instant::now();
+    let mut completed = 0;
+    while started.elapsed() < DURATION {
+        instantiate().await;
+        completed += 1;
+    }
+    completed as f64 / started.elapsed().as_secs_f64()
+}
@@ -0,0 +1,85 @@
+//! Requests per second served by the sample service, once with an instance
+//! created per request and once with warm instances. Run it on two revisions
+//! to compare them:
+//!
+//! ```sh
+//! cargo bench -p apogee-host --bench throughput
+//! ```
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::Client;
+
+use common::{sample_service, Host};
+
+/// Requests in flight at any time.
+const CONCURRENCY: usize = 32;
+
+/// How long each configuration is measured for.
+const DURATION: Duration = Duration::from_secs(10);
+
+const ROUTES: &str = "[routes.\"/\"]\nname = \"guest\"";
+
+#[tokio::main]
+async fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let pooled = dir.path().join("guest");
+    copy_dir(&sample_service(), &pooled);
+    let mut service_toml = std::fs::read_to_string(pooled.join("service.toml")).unwrap();
+    service_toml.push_str(&format!("\n[pooling]\nwarm_instances = {CONCURRENCY}\n"));
+    std::fs::write(pooled.join("service.toml"), service_toml).unwrap();
+
+    for (label, service) in [
+        ("instance per request", sample_service()),
+        ("warm instances", pooled),
+    ] {
+        let host = Host::start(&[service], ROUTES).await;
+        let rate = measure(&host).await;
+        println!("{label}: {rate:.0} requests/s");
+    }
+}
+
+/// Send requests from `CONCURRENCY` tasks for `DURATION`, returning the rate
+/// of successful responses.
+async fn measure(host: &Host) -> f64 {
+    let client = Client::new();
+    let completed = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let tasks = (0..CONCURRENCY)
+        .map(|_| {
+            let (client, completed) = (client.clone(), completed.clone());
+            let uri = host.url("/").parse::<hyper::Uri>().unwrap();
+            tokio::spawn(async move {
+                while started.elapsed() < DURATION {
+                    let res = client.get(uri.clone()).await.unwrap();
+                    assert_eq!(res.status(), 200);
+                    hyper::body::to_bytes(res.into_body()).await.unwrap();
+                    completed.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    completed.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            std::fs::copy(&path, &target).unwrap();
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hyper::body::HttpBody as _;
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+
+/// Per-request access logging, as declared in `config.toml`:
+///
+/// ```toml
+/// [access_log]
+/// format = "combined"
+/// path = "logs/access.log"
+/// max_bytes = 104_857_600
+/// max_files = 5
+/// ```
+///
+/// `common` and `combined` follow the Apache formats exactly; `json` also
+/// records the duration, service and request ID. Without a `path`, entries
+/// are written to standard output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// File to append entries to
+    pub path: Option<PathBuf>,
+    /// Size past which the file is rotated, in bytes
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    /// Number of rotated files to keep, as `access.log.1` and so on
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+/// A handled request, as recorded in the access log.
+#[derive(Debug)]
+pub struct Entry {
+    pub time: OffsetDateTime,
+    pub remote_addr: Option<SocketAddr>,
+    pub method: String,
+    pub uri: String,
+    pub version: hyper::Version,
+    pub status: u16,
+    /// Bytes of response body sent
+    pub bytes: u64,
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub service: Option<String>,
+    pub request_id: String,
+}
+
+/// Writes access log entries on a dedicated thread, so that requests never
+/// wait on the disk.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> anyhow::Result<Self> {
+        let mut sink = match &config.path {
+            Some(path) => Sink::File(RotatingFile::open(
+                path.clone(),
+                config.max_bytes,
+                config.max_files,
+            )?),
+            None => Sink::Stdout,
+        };
+        let (lines, mut receiver) = mpsc::unbounded_channel::<String>();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                while let Some(line) = receiver.blocking_recv() {
+                    if let Err(e) = sink.write(&line) {
+                        tracing::error!("Error writing access log: {e}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            format: config.format,
+            lines,
+        })
+    }
+
+    /// Log `entry` once the body of `res` has been sent. Bodies of unknown
+    /// size are counted as they are streamed to the client.
+    pub fn log_response(&self, mut entry: Entry, res: Response<Body>) -> Response<Body> {
+        if let Some(bytes) = res.body().size_hint().exact() {
+            entry.bytes = bytes;
+            self.log(&entry);
+            return res;
+        }
+
+        let (parts, mut body) = res.into_parts();
+        let (mut sender, counted) = Body::channel();
+        let log = self.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        sender.abort();
+                        break;
+                    }
+                };
+                let len = chunk.len() as u64;
+                if sender.send_data(chunk).await.is_err() {
+                    // The client went away.
+                    break;
+                }
+                entry.bytes += len;
+            }
+            log.log(&entry);
+        });
+        Response::from_parts(parts, counted)
+    }
+
+    fn log(&self, entry: &Entry) {
+        let _ = self.lines.send(self.format(entry));
+    }
+
+    fn format(&self, entry: &Entry) -> String {
+        match self.format {
+            AccessLogFormat::Common => common(entry),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                common(entry),
+                entry.referer.as_deref().map_or("-".into(), escape),
+                entry.user_agent.as_deref().map_or("-".into(), escape),
+            ),
+            AccessLogFormat::Json => serde_json::json!({
+                "time": entry.time.format(&Rfc3339).unwrap_or_default(),
+                "remote_addr": entry.remote_addr.map(|addr| addr.ip().to_string()),
+                "method": entry.method,
+                "uri": entry.uri,
+                "version": format!("{:?}", entry.version),
+                "status": entry.status,
+                "bytes": entry.bytes,
+                "duration_ms": entry.duration.as_secs_f64() * 1000.0,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+                "service": entry.service,
+                "request_id": entry.request_id,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// `host ident authuser [date] "request" status bytes`
+fn common(entry: &Entry) -> String {
+    let time = entry
+        .time
+        .format(format_description!(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+        ))
+        .unwrap_or_default();
+    let host = entry
+        .remote_addr
+        .map_or("-".to_string(), |addr| addr.ip().to_string());
+    let bytes = match entry.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+    format!(
+        "{host} - - [{time}] \"{} {} {:?}\" {} {bytes}",
+        escape(&entry.method),
+        escape(&entry.uri),
+        entry.version,
+        entry.status,
+    )
+}
+
+/// Escape a value for use inside a quoted field.
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => writeln!(std::io::stdout().lock(), "{line}"),
+            Sink::File(file) => file.write(line),
+        }
+    }
+}
+
+/// A file that is renamed to `<path>.1`, shifting older files along, once it
+/// grows past a size.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = rotated(&self.path, index);
+                if from.exists() {
+                    std::fs::rename(from, rotated(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
@@ -0,0 +1,490 @@
+use std::convert::Infallible;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use hyper::body::HttpBody as _;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use crate::compile;
+use crate::config::Config;
+use crate::listen::ListenAddr;
+use crate::server::ConnInfo;
+use crate::service::{Service, ServiceConfig};
+use crate::state::SharedState;
+
+/// Directory, next to the configuration file, that uploaded service files are
+/// staged in until they are deployed.
+const STAGING_DIR: &str = ".apogee-staging";
+
+/// Largest file accepted by an upload.
+const MAX_UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The admin listener, as declared in `config.toml`:
+///
+/// ```toml
+/// [admin]
+/// listen = "127.0.0.1:9000"
+/// token = "..."
+/// ```
+///
+/// It is kept apart from the public listeners so that it can be bound to a
+/// private interface. `/metrics` and `/health` are always served; the
+/// endpoints that manage services require `Authorization: Bearer <token>` and
+/// are disabled unless a token is configured:
+///
+/// - `GET /services` and `GET /routes` list the loaded services and routes.
+/// - `PUT /services/<name>/files/<path>` stages a file of a service, such as
+///   its `service.toml` or component.
+/// - `POST /services/<name>/deploy` loads the staged files to validate them,
+///   then moves them to the `<name>` directory, replacing the previous version.
+/// - `DELETE /services/<name>` removes a service that no route refers to.
+/// - `PUT /routes?prefix=<prefix>[&host=<host>]` points a route at the service
+///   named in the `{"name": ...}` body, writing it to `config.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub listen: ListenAddr,
+    /// Bearer token required by the service management endpoints
+    pub token: Option<String>,
+}
+
+/// An admin API failure, reported to the client as a JSON error.
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self(status, message.to_string())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+/// Serve a request to the admin API.
+pub async fn handle(
+    shared: Arc<SharedState>,
+    _conn: ConnInfo,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    let res = match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["metrics"]) => Ok(Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(shared.metrics.render()))
+            .unwrap()),
+        (&Method::GET, ["health"]) => Ok(health(&shared)),
+        _ => match authorize(&shared, &req) {
+            Ok(()) => api(&shared, req, &segments).await,
+            Err(e) => Err(e),
+        },
+    };
+    Ok(res.unwrap_or_else(|ApiError(status, message)| {
+        if status.is_server_error() {
+            tracing::error!(status = status.as_u16(), "Admin API error: {message}");
+        }
+        json_response(status, json!({ "error": message }))
+    }))
+}
+
+fn authorize(shared: &SharedState, req: &Request<Body>) -> Result<(), ApiError> {
+    let config = shared.current().config.admin.clone();
+    let token = config
+        .and_then(|admin| admin.token)
+        .ok_or_else(|| ApiError::new(StatusCode::FORBIDDEN, "No admin token is configured"))?;
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid admin token"));
+    }
+    Ok(())
+}
+
+/// Compare two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn api(shared: &Arc<SharedState>, req: Request<Body>, segments: &[&str]) -> ApiResult {
+    match (req.method(), segments) {
+        (&Method::GET, ["services"]) => list_services(shared),
+        (&Method::GET, ["routes"]) => list_routes(shared),
+        (&Method::PUT, ["routes"]) => put_route(shared, req).await,
+        (&Method::PUT, ["services", name, "files", file @ ..]) if !file.is_empty() => {
+            let name = service_name(name)?;
+            let file = file.join("/");
+            upload(name, &file, req).await
+        }
+        (&Method::POST, ["services", name, "deploy"]) => deploy(shared, service_name(name)?).await,
+        (&Method::DELETE, ["services", name]) => remove(shared, service_name(name)?).await,
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown endpoint")),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Service names double as directory names, so keep them to a safe set of
+/// characters. Dots are excluded so that a name cannot refer to a file such
+/// as `config.toml`.
+fn service_name(name: &str) -> Result<&str, ApiError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+    if !valid {
+        let message = format!("Invalid service name {name:?}");
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+    }
+    Ok(name)
+}
+
+/// Report services that failed to load and routes to missing services, with
+/// `503 Service Unavailable` if there are any, so that the host is only
+/// considered ready once it can serve every route.
+fn health(shared: &SharedState) -> Response<Body> {
+    let state = shared.current();
+    let healthy = state.load_errors.is_empty() && state.missing_services.is_empty();
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(
+        status,
+        json!({
+            "status": if healthy { "ok" } else { "degraded" },
+            "services": state.services.len(),
+            "load_errors": state.load_errors,
+            "missing_services": state.missing_services,
+        }),
+    )
+}
+
+fn list_services(shared: &SharedState) -> ApiResult {
+    let state = shared.current();
+    let routed = state.config.routed_services().collect::<Vec<_>>();
+    let mut services = state.services.values().collect::<Vec<_>>();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    let services = services
+        .into_iter()
+        .map(|service| {
+            json!({
+                "name": service.name,
+                "directory": service.directory,
+                "wasm": service.config.wasm,
+                "pooled": service.pool.is_some(),
+                "limits": service.config.limits,
+                "routed": routed.contains(&service.name.as_str()),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json_response(StatusCode::OK, json!(services)))
+}
+
+fn list_routes(shared: &SharedState) -> ApiResult {
+    let state = shared.current();
+    Ok(json_response(
+        StatusCode::OK,
+        json!({ "routes": state.config.routes, "hosts": state.config.hosts }),
+    ))
+}
+
+/// Write a request body to a file of a service's staging directory.
+async fn upload(name: &str, file: &str, req: Request<Body>) -> ApiResult {
+    let relative = Path::new(file);
+    if !is_contained(relative) {
+        let message = format!("Invalid file path {file:?}");
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+    }
+    if compile::is_precompiled(relative) {
+        let message = "Precompiled components cannot be uploaded, upload the .wasm instead";
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+    }
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.map_or(false, |length| length > MAX_UPLOAD_BYTES) {
+        return Err(too_large());
+    }
+
+    let path = staging_dir(name).join(relative);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut out = tokio::fs::File::create(&path).await?;
+    let mut body = req.into_body();
+    let mut written = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        written += chunk.len() as u64;
+        if written > MAX_UPLOAD_BYTES {
+            drop(out);
+            tokio::fs::remove_file(&path).await?;
+            return Err(too_large());
+        }
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+
+    Ok(json_response(
+        StatusCode::OK,
+        json!({ "service": name, "file": file, "bytes": written }),
+    ))
+}
+
+/// Whether `path` is relative and stays within the directory it is relative
+/// to.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn too_large() -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Uploads are limited to {MAX_UPLOAD_BYTES} bytes"),
+    )
+}
+
+fn staging_dir(name: &str) -> PathBuf {
+    Path::new(STAGING_DIR).join(name)
+}
+
+/// Validate a service's staged files by loading them, then move them into
+/// place and reload.
+async fn deploy(shared: &Arc<SharedState>, name: &str) -> ApiResult {
+    let staged = staging_dir(name);
+    if !staged.join("service.toml").exists() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("No service.toml has been uploaded for {name}"),
+        ));
+    }
+    // The service's files must all come from what was uploaded
+    let config = tokio::fs::read_to_string(staged.join("service.toml")).await?;
+    let config = toml::from_str::<ServiceConfig>(&config)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let preopened = config.filesystem.iter().map(|entry| &entry.path);
+    for path in std::iter::once(&config.wasm).chain(preopened) {
+        if !is_contained(path) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("{} is outside the service directory", path.display()),
+            ));
+        }
+    }
+
+    // Only ever replace the directory of the service being deployed, never
+    // some other file or directory next to the configuration
+    let target = PathBuf::from(name);
+    let state = shared.current();
+    match state.services.get(name) {
+        Some(existing) if !same_path(&existing.directory, &target) => {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!(
+                    "Service {name} is already loaded from {}",
+                    existing.directory.display()
+                ),
+            ));
+        }
+        None if target.symlink_metadata().is_ok() => {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!("{name} already exists and is not the directory of a loaded service"),
+            ));
+        }
+        _ => {}
+    }
+
+    // Compiling the component is CPU heavy, so keep it off the workers
+    let runtime = shared.runtime.clone();
+    let cache = state.config.compile_cache_dir().map(Path::to_path_buf);
+    let name = name.to_string();
+    let deployed = tokio::task::spawn_blocking({
+        let name = name.clone();
+        move || -> Result<(), ApiError> {
+            let service = Service::load(staged.clone(), &runtime, cache.as_deref(), false)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+            if service.name != name {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("service.toml names the service {}, not {name}", service.name),
+                ));
+            }
+
+            // Swap the directories, keeping the previous version until the new
+            // one is in place
+            let previous = Path::new(STAGING_DIR).join(format!("{name}.previous"));
+            if previous.exists() {
+                std::fs::remove_dir_all(&previous)?;
+            }
+            if target.exists() {
+                std::fs::rename(&target, &previous)?;
+            }
+            if let Err(e) = std::fs::rename(&staged, &target) {
+                if previous.exists() {
+                    std::fs::rename(&previous, &target)?;
+                }
+                return Err(e.into());
+            }
+            if previous.exists() {
+                std::fs::remove_dir_all(&previous)?;
+            }
+            Ok(())
+        }
+    })
+    .await
+    .map_err(|e| anyhow!(e))?;
+    deployed?;
+
+    // Make sure the new directory is loaded if services are listed
+    if !state.config.lists_service_dir(Path::new(&name)) {
+        let mut document = read_config(&shared.config_path).await?;
+        document["services"]
+            .as_array_mut()
+            .ok_or_else(|| anyhow!("services in config.toml is not an array"))?
+            .push(name.as_str());
+        write_config(&shared.config_path, document).await?;
+    }
+
+    shared.reload.notify_one();
+    Ok(json_response(StatusCode::OK, json!({ "deployed": name })))
+}
+
+/// Whether two paths refer to the same existing file or directory.
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Delete a service's directory, unless a route still refers to it.
+async fn remove(shared: &Arc<SharedState>, name: &str) -> ApiResult {
+    let state = shared.current();
+    let service = state
+        .services
+        .get(name)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("No service named {name}")))?;
+    if state.config.routed_services().any(|routed| routed == name) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Service {name} is still referred to by a route"),
+        ));
+    }
+
+    tokio::fs::remove_dir_all(&service.directory).await?;
+    shared.reload.notify_one();
+    Ok(json_response(StatusCode::OK, json!({ "removed": name })))
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteQuery {
+    prefix: String,
+    host: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteTarget {
+    name: String,
+}
+
+/// Point a route at a service, creating the route if needed, and persist the
+/// change to `config.toml`.
+async fn put_route(shared: &Arc<SharedState>, req: Request<Body>) -> ApiResult {
+    let query = req.uri().query().unwrap_or_default();
+    let query = serde_urlencoded::from_str::<RouteQuery>(query)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let target = serde_json::from_slice::<RouteTarget>(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    if !shared.current().services.contains_key(&target.name) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("No service named {}", target.name),
+        ));
+    }
+
+    let mut document = read_config(&shared.config_path).await?;
+    let routes = match &query.host {
+        Some(host) => implicit_table(implicit_table(document.as_item_mut(), "hosts"), host),
+        None => implicit_table(document.as_item_mut(), "routes"),
+    };
+    let route = implicit_table(routes, &query.prefix);
+    if let Some(route) = route.as_table_like_mut() {
+        // Splitting traffic between backends is replaced by the single service
+        route.remove("backends");
+        route.remove("sticky");
+    }
+    route["name"] = toml_edit::value(target.name.as_str());
+    write_config(&shared.config_path, document).await?;
+
+    shared.reload.notify_one();
+    Ok(json_response(
+        StatusCode::OK,
+        json!({ "prefix": query.prefix, "host": query.host, "name": target.name }),
+    ))
+}
+
+/// Read `config.toml` for editing, keeping its comments and layout.
+async fn read_config(config_path: &Path) -> Result<toml_edit::Document, ApiError> {
+    let document = tokio::fs::read_to_string(config_path)
+        .await?
+        .parse::<toml_edit::Document>()
+        .map_err(|e| anyhow!(e))?;
+    Ok(document)
+}
+
+/// Replace `config.toml` with an edited version, refusing to write a
+/// configuration that would fail to load.
+async fn write_config(config_path: &Path, document: toml_edit::Document) -> Result<(), ApiError> {
+    let updated = document.to_string();
+    toml::from_str::<Config>(&updated).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let temporary = config_path.with_extension("toml.tmp");
+    tokio::fs::write(&temporary, updated).await?;
+    tokio::fs::rename(&temporary, config_path).await?;
+    Ok(())
+}
+
+/// The table at `key` in `item`, created without a header of its own if it
+/// does not exist yet.
+fn implicit_table<'a>(item: &'a mut toml_edit::Item, key: &str) -> &'a mut toml_edit::Item {
+    let table = &mut item[key];
+    if table.is_none() {
+        let mut implicit = toml_edit::Table::new();
+        implicit.set_implicit(true);
+        *table = toml_edit::Item::Table(implicit);
+    }
+    table
+}
@@ -0,0 +1,218 @@
+use apogee_sdk::http::imports::http_body;
+use hyper::body::{Bytes, HttpBody as _, Sender};
+use hyper::{Body, Response};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+pub use apogee_sdk::http::imports::http_body::add_to_linker;
+
+use crate::RequestCtx;
+
+/// Why a request body could not be read.
+#[derive(Debug, Clone, Error)]
+pub enum BodyError {
+    #[error("request body exceeds {limit} bytes")]
+    TooLarge { limit: u64 },
+    #[error("malformed request body: {0}")]
+    Malformed(String),
+}
+
+/// A request body that the guest pulls from incrementally.
+pub struct IncomingBody {
+    body: Body,
+    buffered: Bytes,
+    received: u64,
+    limit: Option<u64>,
+}
+
+impl IncomingBody {
+    pub fn new(body: Body, limit: Option<u64>) -> Self {
+        Self {
+            body,
+            buffered: Bytes::new(),
+            received: 0,
+            limit,
+        }
+    }
+
+    async fn read(&mut self, max: usize) -> Result<Option<Vec<u8>>, BodyError> {
+        while self.buffered.is_empty() {
+            let chunk = match self.body.data().await {
+                Some(chunk) => chunk.map_err(|e| BodyError::Malformed(e.to_string()))?,
+                None => return Ok(None),
+            };
+            self.received += chunk.len() as u64;
+            if let Some(limit) = self.limit.filter(|limit| self.received > *limit) {
+                return Err(BodyError::TooLarge { limit });
+            }
+            self.buffered = chunk;
+        }
+        let len = max.min(self.buffered.len());
+        Ok(Some(self.buffered.split_to(len).to_vec()))
+    }
+}
+
+/// A response body written by the guest. Chunks are held until the response
+/// head is sent, then streamed to the client as they are written.
+#[derive(Default)]
+pub struct OutgoingBody {
+    buffered: Vec<Bytes>,
+    sender: Option<Sender>,
+}
+
+impl OutgoingBody {
+    async fn write(&mut self, chunk: Bytes) -> Result<(), String> {
+        match &mut self.sender {
+            Some(sender) => sender
+                .send_data(chunk)
+                .await
+                .map_err(|_| "the client went away".to_string()),
+            None => {
+                self.buffered.push(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    /// Start streaming to the client through `sender`, passing on what was
+    /// written so far.
+    async fn stream(&mut self, sender: Sender) -> Result<(), String> {
+        self.sender = Some(sender);
+        for chunk in std::mem::take(&mut self.buffered) {
+            self.write(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// End a streamed body abruptly, so that the client can tell it is
+    /// incomplete.
+    pub fn abort(self) {
+        if let Some(sender) = self.sender {
+            sender.abort();
+        }
+    }
+
+    /// Convert the written contents into a `hyper` body.
+    pub fn into_body(self) -> Body {
+        Body::from(self.buffered.concat())
+    }
+}
+
+#[async_trait::async_trait]
+impl http_body::HttpBody for RequestCtx {
+    async fn read(
+        &mut self,
+        body: http_body::IncomingBody,
+        max: u32,
+    ) -> anyhow::Result<Result<Option<Vec<u8>>, String>> {
+        let body = self.wasi.table_mut().get_mut::<IncomingBody>(body)?;
+        let result = body.read(max as usize).await;
+        if let Err(e) = &result {
+            // Remember the failure so the host can answer the request itself
+            self.body_error = Some(e.clone());
+        }
+        Ok(result.map_err(|e| e.to_string()))
+    }
+
+    async fn new_outgoing(&mut self) -> anyhow::Result<http_body::OutgoingBody> {
+        let body = self.wasi.table_mut().push(Box::<OutgoingBody>::default())?;
+        self.bodies.push(body);
+        Ok(body)
+    }
+
+    async fn write(
+        &mut self,
+        body: http_body::OutgoingBody,
+        chunk: Vec<u8>,
+    ) -> anyhow::Result<Result<(), String>> {
+        let body = self.wasi.table_mut().get_mut::<OutgoingBody>(body)?;
+        Ok(body.write(chunk.into()).await)
+    }
+
+    async fn send_response(
+        &mut self,
+        status: u16,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
+        body: http_body::OutgoingBody,
+    ) -> anyhow::Result<Result<(), String>> {
+        self.wasi.table().get::<OutgoingBody>(body)?;
+        let head = match self.head.take() {
+            Some(head) => head,
+            None => return Ok(Err("the response was already sent".to_string())),
+        };
+        let mut res = Response::builder().status(status);
+        for (key, value) in headers {
+            res = res.header(key, value);
+        }
+        let (sender, client_body) = Body::channel();
+        let res = match res.body(client_body) {
+            Ok(res) => res,
+            Err(e) => {
+                self.head = Some(head);
+                return Ok(Err(format!("invalid response: {e}")));
+            }
+        };
+        if head.send(res).is_err() {
+            return Ok(Err("the client went away".to_string()));
+        }
+        self.streamed_body = Some(body);
+        let body = self.wasi.table_mut().get_mut::<OutgoingBody>(body)?;
+        Ok(body.stream(sender).await)
+    }
+}
+
+impl RequestCtx {
+    /// Register a request body so that the guest can read it, failing reads
+    /// once more than `limit` bytes have been received.
+    pub fn push_incoming_body(
+        &mut self,
+        body: Body,
+        limit: Option<u64>,
+    ) -> anyhow::Result<http_body::IncomingBody> {
+        let body = IncomingBody::new(body, limit);
+        let body = self.wasi.table_mut().push(Box::new(body))?;
+        self.bodies.push(body);
+        Ok(body)
+    }
+
+    /// Expect the response head of the current request on `head`, for
+    /// guests that send it before returning.
+    pub fn set_response_head(&mut self, head: oneshot::Sender<Response<Body>>) {
+        self.head = Some(head);
+        self.streamed_body = None;
+    }
+
+    /// Take the body whose head the guest already sent, if any.
+    pub fn take_streamed_body(&mut self) -> Option<OutgoingBody> {
+        let body = self.streamed_body.take()?;
+        self.take_outgoing_body(body).ok()
+    }
+
+    /// Take ownership of a response body written by the guest.
+    pub fn take_outgoing_body(
+        &mut self,
+        body: http_body::OutgoingBody,
+    ) -> anyhow::Result<OutgoingBody> {
+        // Validate the handle's type before removing it from the table.
+        self.wasi.table().get::<OutgoingBody>(body)?;
+        self.bodies.retain(|handle| *handle != body);
+        let body = self.wasi.table_mut().delete(body).unwrap();
+        Ok(*body.downcast::<OutgoingBody>().unwrap())
+    }
+
+    /// Drop every body created during the current request, so that a reused
+    /// instance starts the next request with an empty table.
+    pub fn release_bodies(&mut self) {
+        for body in std::mem::take(&mut self.bodies) {
+            self.wasi.table_mut().delete(body);
+        }
+        self.body_error = None;
+        self.head = None;
+        self.streamed_body = None;
+    }
+
+    /// The error the guest hit while reading the request body, if any.
+    pub fn take_body_error(&mut self) -> Option<BodyError> {
+        self.body_error.take()
+    }
+}
@@ -1,4 +1,8 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::listen::ListenAddr;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -6,4 +10,33 @@ pub struct Args {
    /// Path to the configuration file
    #[arg(short, long, default_value="config.toml")]
    pub config: String,
+
+   /// Address to listen on, overriding `listen` in the configuration file.
+   /// May be repeated; accepts `host:port`, `[::1]:port` or `unix:/path`.
+   #[arg(short, long)]
+   pub listen: Vec<ListenAddr>,
+
+   /// Format of the log output. The level is set through `RUST_LOG`.
+   #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+   pub log_format: LogFormat,
+
+   #[command(subcommand)]
+   pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+   /// Compile components ahead of time into `.cwasm` files, which
+   /// `service.toml` can refer to instead of the `.wasm` file
+   Precompile {
+      /// Service directories, or component files
+      #[arg(required = true)]
+      inputs: Vec<PathBuf>,
+   },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+   Text,
+   Json,
 }
\ No newline at end of file
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use wasmtime::component::Component;
+
+use crate::runtime::Runtime;
+use crate::service::ServiceConfig;
+
+/// Directory, next to the configuration file, holding compiled components.
+pub const CACHE_DIR: &str = ".apogee-cache";
+
+/// Extension of components compiled ahead of time.
+pub const PRECOMPILED_EXTENSION: &str = "cwasm";
+
+/// Whether `path` names a component compiled ahead of time.
+pub fn is_precompiled(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension == PRECOMPILED_EXTENSION)
+}
+
+/// Load the component at `path`, returning it along with the cache entry it
+/// was loaded from or stored in.
+///
+/// A `.cwasm` file is taken to be the output of `apogee precompile` and is
+/// loaded without compilation, if `allow_precompiled`. Anything else is
+/// compiled, reusing the compiled artifact in `cache` if the same component
+/// was compiled for the same engine before.
+pub fn load_component(
+    runtime: &Runtime,
+    path: &Path,
+    cache: Option<&Path>,
+    allow_precompiled: bool,
+) -> anyhow::Result<(Component, Option<PathBuf>)> {
+    if is_precompiled(path) {
+        if !allow_precompiled {
+            return Err(anyhow!(
+                "Precompiled components run as native code, so {} must be deployed by \
+                 the operator rather than uploaded",
+                path.display()
+            ));
+        }
+        // SAFETY: precompiled components are produced by `apogee precompile`
+        // and placed by the operator, as the admin API refuses them.
+        // Deserialization checks that they were compiled for a compatible
+        // engine.
+        let component = unsafe { Component::deserialize_file(&runtime.engine, path) }
+            .map_err(|e| anyhow!("Error loading precompiled {}: {e}", path.display()))?;
+        return Ok((component, None));
+    }
+
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return Ok((Component::from_file(&runtime.engine, path)?, None)),
+    };
+    let bytes = std::fs::read(path)?;
+    let cached = cache
+        .join(cache_key(runtime, &bytes))
+        .with_extension(PRECOMPILED_EXTENSION);
+    if cached.exists() {
+        // SAFETY: the cache directory is only written to by the host.
+        match unsafe { Component::deserialize_file(&runtime.engine, &cached) } {
+            Ok(component) => return Ok((component, Some(cached))),
+            Err(e) => tracing::warn!("Ignoring cached {}: {e}", cached.display()),
+        }
+    }
+
+    let component = Component::new(&runtime.engine, &bytes)?;
+    match store(&component, &cached) {
+        Ok(()) => Ok((component, Some(cached))),
+        Err(e) => {
+            tracing::warn!("Error caching compiled {}: {e}", path.display());
+            Ok((component, None))
+        }
+    }
+}
+
+/// Identify a component's compiled form by its contents and by the engine it
+/// is compiled for.
+fn cache_key(runtime: &Runtime, bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(&runtime.fingerprint);
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Remove the compiled components in `cache` other than those in `keep`, so
+/// that entries for components that changed do not pile up.
+pub fn prune_cache<'a>(cache: &Path, keep: impl IntoIterator<Item = &'a Path>) {
+    let keep = keep.into_iter().collect::<Vec<_>>();
+    let entries = std::fs::read_dir(cache).into_iter().flatten().flatten();
+    for path in entries.map(|entry| entry.path()) {
+        if is_precompiled(&path) && !keep.contains(&path.as_path()) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("Error removing cached {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Write a compiled component, going through a temporary file so that a
+/// concurrent load never sees a partial artifact.
+fn store(component: &Component, path: &Path) -> anyhow::Result<()> {
+    let dir = path.parent().ok_or_else(|| anyhow!("Invalid cache path"))?;
+    std::fs::create_dir_all(dir)?;
+    let mut temporary = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut temporary, &component.serialize()?)?;
+    temporary.persist(path)?;
+    Ok(())
+}
+
+/// Compile the component of each service directory, or each component file,
+/// into a `.cwasm` file next to it. Returns the paths written.
+pub fn precompile(inputs: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let default_runtime = Runtime::new(None)?;
+    let mut written = Vec::new();
+    for input in inputs {
+        let (wasm, pooled_runtime) = if input.is_dir() {
+            let service_file = input.join("service.toml");
+            let config =
+                toml::from_str::<ServiceConfig>(std::fs::read_to_string(&service_file)?.as_str())
+                    .map_err(|e| anyhow!("Error reading {}: {e}", service_file.display()))?;
+            // Pooled services run on an engine of their own
+            let runtime = config
+                .pooling
+                .as_ref()
+                .map(|pooling| Runtime::new(Some(pooling)))
+                .transpose()?;
+            (input.join(&config.wasm), runtime)
+        } else {
+            (input.clone(), None)
+        };
+        let runtime = pooled_runtime.as_ref().unwrap_or(&default_runtime);
+
+        let component = Component::from_file(&runtime.engine, &wasm)
+            .map_err(|e| anyhow!("Error compiling {}: {e}", wasm.display()))?;
+        let output = wasm.with_extension(PRECOMPILED_EXTENSION);
+        std::fs::write(&output, component.serialize()?)?;
+        written.push(output);
+    }
+    Ok(written)
+}
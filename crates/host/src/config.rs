@@ -1,77 +1,371 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
-use patricia_tree::PatriciaMap;
-use serde::{Deserialize, Serialize};
+use anyhow::anyhow;
+use hyper::header::COOKIE;
+use hyper::HeaderMap;
+use path_clean::PathClean;
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::access_log::AccessLogConfig;
+use crate::admin::AdminConfig;
+use crate::compile;
+use crate::listen::{default_listen, ListenAddr};
+use crate::routes::Routes;
+use crate::tls::TlsConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    #[serde(with = "serialization")]
-    pub routes: PatriciaMap<ServiceDescription>,
+    /// Addresses to accept connections on
+    #[serde(default = "default_listen")]
+    pub listen: Vec<ListenAddr>,
+    /// HTTPS listeners and their certificates
+    pub tls: Option<TlsConfig>,
+    /// Listener for the admin API, disabled if absent
+    pub admin: Option<AdminConfig>,
+    /// Access logging, disabled if absent
+    pub access_log: Option<AccessLogConfig>,
+    /// Keep compiled components in `.apogee-cache` so that unchanged
+    /// components are not recompiled on startup
+    #[serde(default = "default_compile_cache")]
+    pub compile_cache: bool,
+    /// Service directories, relative to this file, which may be glob patterns
+    /// such as `"services/*"`. Every directory next to this file that holds a
+    /// `service.toml` is loaded if absent. Services deployed through the admin
+    /// API are placed next to this file and added to the list if it does not
+    /// match them already.
+    pub services: Option<Vec<String>>,
+    /// Refuse to start, or to apply a reload, if any service fails to load
+    /// or a route refers to a service that does not exist
+    #[serde(default)]
+    pub strict: bool,
+    /// How long in-flight requests may run after a shutdown signal before
+    /// they are interrupted, in milliseconds
+    #[serde(default = "default_drain_timeout_ms")]
+    pub drain_timeout_ms: u64,
+    /// HTML template served for errors raised by the host. `{status}` and
+    /// `{reason}` are replaced by the status code and reason phrase.
+    pub error_page: Option<PathBuf>,
+    /// Routes for requests whose host has no entry in `hosts`
+    #[serde(default)]
+    pub routes: Routes,
+    /// Routes per virtual host, keyed by host name. A leading `*.` matches
+    /// any subdomain:
+    ///
+    /// ```toml
+    /// [hosts."api.example.com"."/"]
+    /// name = "api"
+    ///
+    /// [hosts."*.example.com"."/"]
+    /// name = "www"
+    /// ```
+    #[serde(default, deserialize_with = "lowercase_keys")]
+    pub hosts: HashMap<String, Routes>,
 }
 
 impl Config {
-    pub fn route(&self, path: impl AsRef<str>) -> Option<&ServiceDescription> {
-        let path = path.as_ref();
-        let longest_prefix = self.routes.get_longest_common_prefix(path)?;
-        let prefix = std::str::from_utf8(longest_prefix.0).unwrap();
-        let remainder = path.strip_prefix(std::str::from_utf8(longest_prefix.0).unwrap())?;
-
-        if !prefix.ends_with('/') && (!remainder.starts_with('/') && !remainder.is_empty()) {
-            return None;
-        }
+    /// Names of every service that requests are routed to, on any host.
+    pub fn routed_services(&self) -> impl Iterator<Item = &str> {
+        self.routes
+            .service_names()
+            .chain(self.hosts.values().flat_map(Routes::service_names))
+    }
+
+    /// Where compiled components are cached, if caching is enabled.
+    pub fn compile_cache_dir(&self) -> Option<&Path> {
+        self.compile_cache.then(|| Path::new(compile::CACHE_DIR))
+    }
 
-        Some(longest_prefix.1)
+    /// Whether the directory `dir`, relative to this file, is loaded as a
+    /// service.
+    pub fn lists_service_dir(&self, dir: &Path) -> bool {
+        let entries = match &self.services {
+            Some(entries) => entries,
+            None => return true,
+        };
+        entries.iter().any(|entry| {
+            let pattern = glob::Pattern::new(entry);
+            Path::new(entry).clean() == dir
+                || pattern.map_or(false, |pattern| pattern.matches_path(dir))
+        })
     }
+
+    /// The routing table for requests addressed to `host`. The most specific
+    /// wildcard wins when several match.
+    pub fn routes_for(&self, host: Option<&str>) -> &Routes {
+        let host = match host {
+            Some(host) if !self.hosts.is_empty() => host.to_ascii_lowercase(),
+            _ => return &self.routes,
+        };
+        if let Some(routes) = self.hosts.get(&host) {
+            return routes;
+        }
+        let mut parent = host.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(routes) = self.hosts.get(&format!("*.{rest}")) {
+                return routes;
+            }
+            parent = rest;
+        }
+        &self.routes
+    }
+}
+
+fn lowercase_keys<'de, D>(deserializer: D) -> Result<HashMap<String, Routes>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hosts = HashMap::<String, Routes>::deserialize(deserializer)?;
+    Ok(hosts
+        .into_iter()
+        .map(|(host, routes)| (host.to_ascii_lowercase(), routes))
+        .collect())
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_compile_cache() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceDescription {
+    /// The service handling this route
+    pub name: Option<String>,
+    /// Services to split this route's traffic between, instead of `name`:
+    ///
+    /// ```toml
+    /// [routes."/"]
+    /// backends = [
+    ///     { name = "site", weight = 95 },
+    ///     { name = "site-canary", weight = 5 },
+    /// ]
+    /// sticky = { cookie = "session" }
+    /// ```
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<Backend>,
+    /// Send requests carrying the same header or cookie value to the same
+    /// backend
+    pub sticky: Option<Sticky>,
+    /// Methods this route accepts; any method if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// Remove the matched prefix from the path passed to the guest
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replace the path passed to the guest, in which case `strip_prefix` has
+    /// no effect. `{name}` is replaced by the value of the route's parameter
+    /// of that name.
+    pub rewrite: Option<String>,
+    /// Prepend to the path passed to the guest, after stripping or rewriting
+    pub add_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backend {
     pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
 }
 
-mod serialization {
-    use super::*;
-    use serde::de::{Deserializer, Visitor};
-    use serde::ser::Serializer;
-    use std::fmt;
-    use std::marker::PhantomData;
-
-    pub fn serialize<S>(
-        map: &PatriciaMap<ServiceDescription>,
-        serializer: S,
-    ) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let map = map.iter().collect::<HashMap<_, _>>();
-        map.serialize(serializer)
-    }
-
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<PatriciaMap<ServiceDescription>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct PatriciaMapVisitor(PhantomData<fn() -> PatriciaMap<ServiceDescription>>);
-        impl<'de> Visitor<'de> for PatriciaMapVisitor {
-            type Value = PatriciaMap<ServiceDescription>;
-
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a map of strings to strings")
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sticky {
+    Header(String),
+    Cookie(String),
+}
+
+impl ServiceDescription {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match (&self.name, self.backends.is_empty()) {
+            (Some(_), false) => Err(anyhow!("A route cannot have both a name and backends")),
+            (None, true) => Err(anyhow!("A route needs either a name or backends")),
+            (None, false) if self.backends.iter().all(|backend| backend.weight == 0) => {
+                Err(anyhow!("At least one backend needs a non-zero weight"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Names of the services this route sends requests to.
+    pub fn service_names(&self) -> impl Iterator<Item = &str> {
+        self.name
+            .iter()
+            .map(String::as_str)
+            .chain(self.backends.iter().map(|backend| backend.name.as_str()))
+    }
+
+    /// Pick the service to handle a request. Requests are split between
+    /// backends by weight, at random unless the route is sticky and the
+    /// request carries the sticky header or cookie.
+    pub fn select(&self, headers: &HeaderMap) -> &str {
+        if let Some(name) = &self.name {
+            return name;
+        }
+
+        let total = self
+            .backends
+            .iter()
+            .map(|backend| u64::from(backend.weight))
+            .sum::<u64>();
+        let mut point = match self.sticky.as_ref().and_then(|sticky| sticky.key(headers)) {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() % total
             }
+            None => rand::thread_rng().gen_range(0..total),
+        };
+        for backend in &self.backends {
+            let weight = u64::from(backend.weight);
+            if point < weight {
+                return &backend.name;
+            }
+            point -= weight;
+        }
+        unreachable!("point is below the total weight")
+    }
 
-            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::MapAccess<'de>,
-            {
-                let mut patricia_map = PatriciaMap::new();
-                while let Some((key, value)) = map.next_entry::<String, ServiceDescription>()? {
-                    let key = key.trim_end_matches('/');
-                    patricia_map.insert(key, value);
-                }
-                Ok(patricia_map)
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+impl Sticky {
+    fn key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        match self {
+            Sticky::Header(name) => headers.get(name.as_str())?.to_str().ok(),
+            Sticky::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|cookies| cookies.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+    use crate::routes::RouteMatch;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// The service that `/` on `host` is routed to, if any.
+    fn service_for<'a>(config: &'a Config, host: Option<&str>) -> Option<&'a str> {
+        match config.routes_for(host).route("GET", "/") {
+            RouteMatch::Found(route) => route.service.name.as_deref(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn most_specific_host_wins() {
+        let config = config(
+            r#"
+            [routes."/"]
+            name = "default"
+
+            [hosts."api.example.com"."/"]
+            name = "api"
+
+            [hosts."*.example.com"."/"]
+            name = "www"
+
+            [hosts."*.b.example.com"."/"]
+            name = "b"
+            "#,
+        );
+        assert_eq!(service_for(&config, Some("api.example.com")), Some("api"));
+        assert_eq!(service_for(&config, Some("a.b.example.com")), Some("b"));
+        assert_eq!(service_for(&config, Some("b.example.com")), Some("www"));
+        assert_eq!(service_for(&config, Some("c.example.com")), Some("www"));
+        // A wildcard only matches subdomains
+        assert_eq!(service_for(&config, Some("example.com")), Some("default"));
+        assert_eq!(service_for(&config, Some("example.org")), Some("default"));
+        assert_eq!(service_for(&config, None), Some("default"));
+    }
+
+    #[test]
+    fn host_names_are_case_insensitive() {
+        let config = config(
+            r#"
+            [hosts."API.Example.com"."/"]
+            name = "api"
+            "#,
+        );
+        assert_eq!(service_for(&config, Some("api.EXAMPLE.com")), Some("api"));
+        // Without top-level routes, other hosts match nothing
+        assert_eq!(service_for(&config, Some("example.org")), None);
+    }
+
+    fn description(toml: &str) -> ServiceDescription {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn sticky_headers(user: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", HeaderValue::from_str(user).unwrap());
+        headers
+    }
+
+    #[test]
+    fn sticky_key_always_selects_the_same_backend() {
+        let route = description(
+            r#"
+            backends = [{ name = "a" }, { name = "b" }, { name = "c" }]
+            sticky = { header = "x-user" }
+            "#,
+        );
+        for user in ["alice", "bob", "carol", "dave"] {
+            let headers = sticky_headers(user);
+            let first = route.select(&headers);
+            for _ in 0..100 {
+                assert_eq!(route.select(&headers), first, "{user} changed backends");
             }
         }
+    }
+
+    #[test]
+    fn zero_weight_backends_are_never_selected() {
+        let route = description(
+            r#"
+            backends = [{ name = "off", weight = 0 }, { name = "on", weight = 1 }]
+            sticky = { header = "x-user" }
+            "#,
+        );
+        assert!(route.validate().is_ok());
+        for i in 0..1000 {
+            assert_eq!(route.select(&HeaderMap::new()), "on");
+            assert_eq!(route.select(&sticky_headers(&format!("user-{i}"))), "on");
+        }
+    }
 
-        deserializer.deserialize_map(PatriciaMapVisitor(PhantomData))
+    #[test]
+    fn all_zero_weights_are_rejected() {
+        let route = description(
+            r#"backends = [{ name = "a", weight = 0 }, { name = "b", weight = 0 }]"#,
+        );
+        assert!(route.validate().is_err());
     }
 }
@@ -1,14 +1,27 @@
 use std::path::PathBuf;
 
+use hyper::{Body, Response};
 use patricia_tree::PatriciaMap;
+use tokio::sync::oneshot;
 use wasmtime_wasi_host::WasiCtx;
 
+use crate::body::BodyError;
+use crate::limits::RequestLimiter;
 
 #[derive(Default)]
 pub struct RequestCtx {
     pub(crate) wasi: WasiCtx,
     /// Map of <Container Path -> Host Path> for preopened directories
     pub(crate) preopened_dirs: PatriciaMap<PathBuf>,
+    pub(crate) limiter: RequestLimiter,
+    /// Table handles of the request and response bodies of the current request
+    pub(crate) bodies: Vec<u32>,
+    /// Failure encountered while the guest read the request body
+    pub(crate) body_error: Option<BodyError>,
+    /// Where the response head goes if the guest sends it before returning
+    pub(crate) head: Option<oneshot::Sender<Response<Body>>>,
+    /// The body whose head the guest already sent
+    pub(crate) streamed_body: Option<u32>,
 }
 
 impl RequestCtx {
@@ -0,0 +1,73 @@
+use hyper::header::ALLOW;
+use hyper::{Body, Response, StatusCode};
+use thiserror::Error;
+
+use crate::body::BodyError;
+use crate::limits::LimitExceeded;
+
+/// Everything that can go wrong while the host handles a request. Each
+/// variant maps to the status code the client receives; the cause itself is
+/// only logged.
+#[derive(Debug, Error)]
+pub enum HostError {
+    #[error("no route matches the request")]
+    NotFound,
+    #[error("route does not allow method {0}")]
+    MethodNotAllowed(hyper::Method, Vec<String>),
+    #[error(transparent)]
+    Body(#[from] BodyError),
+    #[error("error instantiating component: {0:#}")]
+    Instantiation(anyhow::Error),
+    #[error("guest trapped: {0:#}")]
+    Trap(anyhow::Error),
+    #[error("guest returned an error: {0}")]
+    Guest(String),
+    #[error("request exceeded its {0} limit")]
+    LimitExceeded(LimitExceeded),
+    #[error("interrupted by host shutdown")]
+    Interrupted,
+    #[error("invalid response from guest: {0:#}")]
+    InvalidResponse(anyhow::Error),
+}
+
+impl HostError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HostError::NotFound => StatusCode::NOT_FOUND,
+            HostError::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            HostError::Body(BodyError::TooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+            HostError::Body(BodyError::Malformed(_)) => StatusCode::BAD_REQUEST,
+            HostError::Instantiation(_) | HostError::Trap(_) | HostError::Guest(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            HostError::LimitExceeded(exceeded) => {
+                StatusCode::from_u16(exceeded.status()).unwrap()
+            }
+            HostError::Interrupted => StatusCode::SERVICE_UNAVAILABLE,
+            HostError::InvalidResponse(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Build the response sent to the client, using the configured error page
+    /// template if there is one. `{status}` and `{reason}` in the template are
+    /// replaced by the status code and its canonical reason.
+    pub fn into_response(self, error_page: Option<&str>) -> Response<Body> {
+        let status = self.status();
+        let reason = status.canonical_reason().unwrap_or_default();
+        let (content_type, body) = match error_page {
+            Some(page) => (
+                "text/html; charset=utf-8",
+                page.replace("{status}", status.as_str())
+                    .replace("{reason}", reason),
+            ),
+            None => ("text/plain", format!("{} {reason}", status.as_str())),
+        };
+        let mut res = Response::builder()
+            .status(status)
+            .header("Content-Type", content_type);
+        if let HostError::MethodNotAllowed(_, allowed) = &self {
+            res = res.header(ALLOW, allowed.join(", "));
+        }
+        res.body(Body::from(body)).unwrap()
+    }
+}
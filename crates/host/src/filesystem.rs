@@ -1,4 +1,4 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::path::PathBuf;
 
 use path_clean::PathClean;
 use apogee_sdk::filesystem;
@@ -7,16 +7,17 @@ pub use apogee_sdk::filesystem::imports::add_to_linker;
 
 use crate::RequestCtx;
 
+#[async_trait::async_trait]
 impl filesystem::imports::filesystem::Filesystem for RequestCtx {
-    fn read_file(&mut self, path: String) -> anyhow::Result<Result<Vec<u8>, String>> {
+    async fn read_file(&mut self, path: String) -> anyhow::Result<Result<Vec<u8>, String>> {
         // TODO: Figure out if any errors should be caught by Wasmtime
         // instead of forwarding them to the guest as Strings.
-        Ok(self.read_file(path))
+        Ok(self.read_file(path).await)
     }
 }
 
 impl RequestCtx {
-    fn read_file(&mut self, path: String) -> Result<Vec<u8>, String> {
+    async fn read_file(&mut self, path: String) -> Result<Vec<u8>, String> {
         // Ensure that the path is rooted
         let path = if !path.starts_with('/') {
             format!("/{path}")
@@ -37,9 +38,6 @@ impl RequestCtx {
                 .unwrap(),
         );
 
-        let mut file = File::open(host_path).map_err(|e| e.to_string())?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).map_err(|e| e.to_string())?;
-        Ok(contents)
+        tokio::fs::read(host_path).await.map_err(|e| e.to_string())
     }
 }
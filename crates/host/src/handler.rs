@@ -0,0 +1,406 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST, REFERER, USER_AGENT};
+use hyper::{Body, HeaderMap, Request, Response, Uri};
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+use tracing::{Instrument, Span};
+
+use apogee_sdk::http::imports::{HeaderParam, Method, RequestContext, RouteParam, Version};
+use apogee_sdk::http::imports::{Request as WasmRequest, Response as WasmResponse};
+
+use crate::access_log::Entry;
+use crate::body::{BodyError, OutgoingBody};
+use crate::error::HostError;
+use crate::limits::{self, LimitExceeded};
+use crate::metrics::RequestStats;
+use crate::pool::Instance;
+use crate::routes::{Route, RouteMatch};
+use crate::server::ConnInfo;
+use crate::service::Service;
+use crate::shutdown::Interrupted;
+use crate::state::SharedState;
+
+/// Header carrying the ID that correlates a request with its log events.
+const REQUEST_ID: &str = "x-request-id";
+
+/// Route a request to its service and run it through the service's component.
+pub async fn handle(
+    shared: Arc<SharedState>,
+    conn: ConnInfo,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    // Reuse the ID assigned by a proxy in front of the host, if any
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .filter(|id| id.len() <= 128)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id);
+    let span = tracing::info_span!(
+        "request",
+        id = %request_id,
+        method = %req.method(),
+        uri = %req.uri(),
+        service = tracing::field::Empty,
+        route = tracing::field::Empty,
+    );
+
+    // Capture what the access log needs before the request is consumed
+    let start = Instant::now();
+    let entry = shared.access_log.as_ref().map(|_| Entry {
+        time: OffsetDateTime::now_utc(),
+        remote_addr: conn.peer_addr,
+        method: req.method().to_string(),
+        uri: req.uri().to_string(),
+        version: req.version(),
+        status: 0,
+        bytes: 0,
+        duration: Duration::ZERO,
+        referer: header(req.headers(), REFERER),
+        user_agent: header(req.headers(), USER_AGENT),
+        service: None,
+        request_id: request_id.clone(),
+    });
+
+    let (mut res, service) = dispatch(shared.clone(), conn, req).instrument(span).await;
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID, request_id);
+    }
+    if let (Some(access_log), Some(mut entry)) = (&shared.access_log, entry) {
+        entry.status = res.status().as_u16();
+        entry.duration = start.elapsed();
+        entry.service = service;
+        res = access_log.log_response(entry, res);
+    }
+    Ok(res)
+}
+
+async fn dispatch(
+    shared: Arc<SharedState>,
+    conn: ConnInfo,
+    req: Request<Body>,
+) -> (Response<Body>, Option<String>) {
+    let _in_flight = shared.shutdown.track();
+    let start = Instant::now();
+
+    // Keep using this version of the state even if it is reloaded meanwhile
+    let state = shared.current();
+    let path = req.uri().path().to_string();
+
+    // Route the request to the appropriate service
+    let routes = state.config.routes_for(authority(req.headers(), req.uri()).map(strip_port));
+    let route = routes.route(req.method().as_str(), &path);
+    let backend = match &route {
+        RouteMatch::Found(route) => Some(route.service.select(req.headers())),
+        _ => None,
+    };
+    let service = backend.and_then(|backend| state.services.get(backend));
+    // Backends of a split route are also counted per route, to compare them
+    let split = match &route {
+        RouteMatch::Found(route) if !route.service.backends.is_empty() => Some(route.pattern),
+        _ => None,
+    };
+    let span = Span::current();
+    if let RouteMatch::Found(route) = &route {
+        span.record("route", route.pattern);
+    }
+    if let Some(service) = service {
+        span.record("service", service.name.as_str());
+    }
+
+    let mut stats = RequestStats::default();
+    let result = match (route, service) {
+        (RouteMatch::Found(route), Some(service)) => {
+            run(&shared, service, route, conn, req, &mut stats).await
+        }
+        (RouteMatch::MethodNotAllowed(allowed), _) => {
+            Err(HostError::MethodNotAllowed(req.method().clone(), allowed))
+        }
+        _ => Err(HostError::NotFound),
+    };
+    let res = result.unwrap_or_else(|e| {
+        let status = e.status().as_u16();
+        if e.status().is_server_error() {
+            tracing::error!(status, "{e}");
+        } else {
+            tracing::info!(status, "{e}");
+        }
+        e.into_response(state.error_page.as_deref())
+    });
+    if let Some(service) = service {
+        shared
+            .metrics
+            .record(&service.name, res.status(), start.elapsed(), &stats);
+    }
+    if let (Some(route), Some(backend)) = (split, backend) {
+        shared.metrics.record_backend(route, backend, res.status());
+    }
+    (res, service.map(|service| service.name.clone()))
+}
+
+/// Run a request through an instance of `service`'s component.
+async fn run(
+    shared: &Arc<SharedState>,
+    service: &Arc<Service>,
+    route: Route<'_>,
+    conn: ConnInfo,
+    req: Request<Body>,
+    stats: &mut RequestStats,
+) -> Result<Response<Body>, HostError> {
+    let limits = &service.config.limits;
+
+    // Destructure the request parts and body
+    let (parts, body) = req.into_parts();
+
+    // Convert the `Method` and `Version` from their raw
+    // representation to their corresponding structs. hyper only parses
+    // versions the guest interface knows, so the fallback is never used.
+    let method = Method::from(&parts.method);
+    let version = Version::try_from(parts.version).unwrap_or(Version::HttpV11);
+
+    // Reject bodies that announce they are too large before doing any work
+    if let Some(limit) = limits.max_request_body_bytes {
+        let length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if length.map_or(false, |length| length > limit) {
+            return Err(BodyError::TooLarge { limit }.into());
+        }
+    }
+
+    // Get an instance of the service's component to handle the request
+    let start = Instant::now();
+    let mut instance = service
+        .instantiate()
+        .await
+        .and_then(|mut instance| {
+            limits::apply(&mut instance.store, limits)?;
+            Ok(instance)
+        })
+        .map_err(HostError::Instantiation)?;
+    stats.instantiate = Some(start.elapsed());
+
+    // Get the request URI as a string, rewritten as configured for the route
+    let uri = route.guest_uri(&parts.uri);
+
+    // Describe where the request came from and how it was routed
+    let peer_addr = conn.peer_addr.map(|addr| addr.to_string());
+    let matched_prefix = match route.prefix {
+        "" => "/",
+        prefix => prefix,
+    }
+    .to_string();
+    let path = match route.remainder {
+        "" => "/",
+        remainder => remainder,
+    }
+    .to_string();
+    let params: Vec<(String, String)> = route
+        .params
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    // Attribute the guest's log events to this request
+    instance.store.data_mut().wasi.set_span(Span::current());
+
+    // Hand the request body to the guest as a streaming handle
+    let body = instance
+        .store
+        .data_mut()
+        .push_incoming_body(body, limits.max_request_body_bytes)
+        .map_err(HostError::Instantiation)?;
+
+    // The guest may send the response head before it returns
+    let (head, mut sent_head) = oneshot::channel();
+    instance.store.data_mut().set_response_head(head);
+
+    // The call owns everything it uses, so that it can carry on streaming the
+    // response body after the head has been passed to the client
+    let shared = shared.clone();
+    let call_limits = limits.clone();
+    let call = async move {
+        let _in_flight = shared.shutdown.track();
+
+        // Convert the request headers to a vector of `HeaderParam`
+        let headers: Vec<HeaderParam> = parts
+            .headers
+            .iter()
+            .map(|(key, value)| HeaderParam {
+                key: key.as_str().as_bytes(),
+                value: value.as_bytes(),
+            })
+            .collect();
+        let params: Vec<RouteParam> = params
+            .iter()
+            .map(|(name, value)| RouteParam { name, value })
+            .collect();
+        let context = RequestContext {
+            peer_addr: peer_addr.as_deref(),
+            matched_prefix: &matched_prefix,
+            path: &path,
+            params: params.as_slice(),
+            query: parts.uri.query(),
+            scheme: conn.scheme,
+            host: authority(&parts.headers, &parts.uri),
+        };
+
+        // Create a `WasmRequest` from the request parts
+        let req = WasmRequest {
+            version,
+            method: Method::from(&parts.method),
+            uri: uri.as_str(),
+            headers: headers.as_slice(),
+            body,
+            context,
+        };
+
+        // Call the `handle_http_request` method on the `Http` instance
+        let call = instance
+            .component
+            .handle_http_request(&mut instance.store, req);
+        let call = limits::with_deadline(&call_limits, call);
+        let res = shared.shutdown.interruptible(call).await;
+        (instance, res)
+    };
+
+    let start = Instant::now();
+    let mut call = Box::pin(call);
+    let (mut instance, res) = tokio::select! {
+        output = &mut call => output,
+        Ok(head) = &mut sent_head => {
+            stats.execution = Some(start.elapsed());
+            let service = service.clone();
+            let streaming = async move {
+                let (instance, res) = call.await;
+                finish_streamed(&service, instance, res);
+            };
+            tokio::spawn(streaming.in_current_span());
+            return Ok(head);
+        }
+    };
+    stats.execution = Some(start.elapsed());
+    stats.fuel_consumed = limits::fuel_used(&instance.store);
+    stats.memory_peak = limits::memory_peak(&instance.store);
+    if let Ok(head) = sent_head.try_recv() {
+        // The head was sent just before the call completed
+        finish_streamed(service, instance, res);
+        return Ok(head);
+    }
+
+    // An instance whose call failed is dropped rather than released
+    let res = match res {
+        Ok(res) => res,
+        Err(e) if e.is::<Interrupted>() => return Err(HostError::Interrupted),
+        Err(e) => match limits::exceeded(&instance.store, &e) {
+            Some(exceeded) => {
+                tracing::warn!(
+                    limit = %exceeded,
+                    fuel_consumed = stats.fuel_consumed,
+                    "Request exceeded its execution budget"
+                );
+                stats.trapped = exceeded != LimitExceeded::Timeout;
+                return Err(HostError::LimitExceeded(exceeded));
+            }
+            None => {
+                stats.trapped = true;
+                return Err(HostError::Trap(e));
+            }
+        },
+    };
+
+    // A failure to read the request body takes precedence over whatever the
+    // guest made of it
+    let body_error = instance.store.data_mut().take_body_error();
+
+    // Hand the guest-written body to the client
+    let res: Result<WasmResponse, String> = res;
+    let res = res.map(|res| {
+        let body = instance
+            .store
+            .data_mut()
+            .take_outgoing_body(res.body)
+            .map(OutgoingBody::into_body);
+        (res, body)
+    });
+    instance.store.data_mut().wasi.set_span(Span::none());
+    service.release(instance);
+
+    if let Some(e) = body_error {
+        return Err(e.into());
+    }
+    let (res, body) = res.map_err(HostError::Guest)?;
+    let body = body.map_err(HostError::InvalidResponse)?;
+
+    // Pass the body through untouched; only the status and headers
+    // chosen by the guest need validating.
+    res.try_into_hyper_response(body)
+        .map_err(|e| HostError::InvalidResponse(e.into()))
+}
+
+/// Complete a call whose guest already sent the response head. The body ends
+/// normally if the call succeeded, and is aborted otherwise so that the client
+/// does not mistake it for a complete one.
+fn finish_streamed(
+    service: &Service,
+    mut instance: Instance,
+    res: anyhow::Result<Result<WasmResponse, String>>,
+) {
+    let ctx = instance.store.data_mut();
+    ctx.wasi.set_span(Span::none());
+    let body = ctx.take_streamed_body();
+    let error = match res {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e),
+        Err(e) => Some(match limits::exceeded(&instance.store, &e) {
+            Some(exceeded) => format!("request exceeded its {exceeded} limit"),
+            None => format!("{e:#}"),
+        }),
+    };
+    if let Some(e) = error {
+        tracing::error!("Response body cut short: {e}");
+        if let Some(body) = body {
+            body.abort();
+        }
+        return;
+    }
+    // Dropping the body ends it
+    drop(body);
+    service.release(instance);
+}
+
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// A random ID for a request that did not come with one.
+fn generate_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// The host a request was addressed to, from its `Host` header or, for
+/// HTTP/2, its `:authority`.
+fn authority<'a>(headers: &'a HeaderMap, uri: &'a Uri) -> Option<&'a str> {
+    headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+}
+
+/// Remove the port from a `host[:port]` authority.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal
+        return host.split_once(']').map_or(host, |(addr, _)| &addr[1..]);
+    }
+    host.rsplit_once(':').map_or(host, |(host, _)| host)
+}
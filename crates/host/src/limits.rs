@@ -0,0 +1,184 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+use wasmtime::{Engine, ResourceLimiter, Store, Trap};
+
+use crate::ctx::RequestCtx;
+
+/// How often the engine's epoch is incremented. Guests yield back to the
+/// runtime on every tick, which is the granularity at which wall-clock
+/// deadlines are enforced.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Fuel granted to requests of services that do not declare a budget.
+const UNLIMITED_FUEL: u64 = i64::MAX as u64;
+
+/// Execution budget for a single request, as declared in `service.toml`:
+///
+/// ```toml
+/// [limits]
+/// fuel = 10_000_000
+/// timeout_ms = 500
+/// max_memory_bytes = 67_108_864
+/// max_request_body_bytes = 10_485_760
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceLimits {
+    /// Maximum amount of fuel a request may consume.
+    pub fuel: Option<u64>,
+    /// Wall-clock deadline for a request, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// Maximum size of the guest's linear memory, in bytes.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum size of a request body, in bytes.
+    pub max_request_body_bytes: Option<u64>,
+}
+
+/// The way in which a request exceeded its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Fuel,
+    Timeout,
+    Memory,
+}
+
+impl LimitExceeded {
+    /// The HTTP status code returned to the client.
+    pub fn status(&self) -> u16 {
+        match self {
+            LimitExceeded::Timeout => 504,
+            LimitExceeded::Fuel | LimitExceeded::Memory => 503,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Fuel => write!(f, "fuel"),
+            LimitExceeded::Timeout => write!(f, "timeout"),
+            LimitExceeded::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Caps the linear memory of a request's store, remembering whether the cap
+/// was ever hit so that a subsequent trap can be attributed to it. Also holds
+/// the per-request state needed to reuse a store across requests.
+#[derive(Debug, Default)]
+pub struct RequestLimiter {
+    max_memory_bytes: Option<usize>,
+    memory_exceeded: bool,
+    /// Largest size the store's memory has grown to. Memory never shrinks, so
+    /// this carries over when the store is reused.
+    memory_peak: usize,
+    fuel_baseline: u64,
+}
+
+impl ResourceLimiter for RequestLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, maximum: Option<usize>) -> bool {
+        let allowed = self.max_memory_bytes.map_or(true, |max| desired <= max)
+            && maximum.map_or(true, |max| desired <= max);
+        if allowed {
+            self.memory_peak = self.memory_peak.max(desired);
+        } else {
+            self.memory_exceeded = true;
+        }
+        allowed
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        maximum.map_or(true, |max| desired <= max)
+    }
+}
+
+/// Enable the engine features that execution limits rely on.
+pub fn configure_engine(config: &mut wasmtime::Config) {
+    config.consume_fuel(true);
+    config.epoch_interruption(true);
+}
+
+/// Periodically increment the engine's epoch so that stores can observe
+/// their deadlines. The ticker keeps the engine alive until it is aborted.
+pub fn spawn_epoch_ticker(engine: Engine) -> AbortHandle {
+    let ticker = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EPOCH_TICK);
+        loop {
+            interval.tick().await;
+            engine.increment_epoch();
+        }
+    });
+    ticker.abort_handle()
+}
+
+/// Install the limiter on a freshly created store, and make its guest yield
+/// to the async runtime on every epoch tick.
+pub fn configure_store(store: &mut Store<RequestCtx>) {
+    store.limiter(|ctx| &mut ctx.limiter);
+    store.epoch_deadline_async_yield_and_update(1);
+}
+
+/// Reset a store's budget to a service's limits before handling a request.
+pub fn apply(store: &mut Store<RequestCtx>, limits: &ServiceLimits) -> anyhow::Result<()> {
+    let budget = limits.fuel.unwrap_or(UNLIMITED_FUEL);
+    let remaining = store.consume_fuel(0)?;
+    if remaining < budget {
+        store.add_fuel(budget - remaining)?;
+    } else {
+        store.consume_fuel(remaining - budget)?;
+    }
+    let fuel_baseline = store.fuel_consumed().unwrap_or_default();
+
+    let limiter = &mut store.data_mut().limiter;
+    limiter.max_memory_bytes = limits.max_memory_bytes;
+    limiter.memory_exceeded = false;
+    limiter.fuel_baseline = fuel_baseline;
+    Ok(())
+}
+
+/// Run a guest call, failing with [`LimitExceeded::Timeout`] if it outlives
+/// the service's deadline. A call that timed out leaves its instance
+/// mid-execution, so the instance must not be reused.
+pub async fn with_deadline<T>(
+    limits: &ServiceLimits,
+    call: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match limits.timeout_ms {
+        Some(timeout) => tokio::time::timeout(Duration::from_millis(timeout), call)
+            .await
+            .unwrap_or_else(|_| Err(LimitExceeded::Timeout.into())),
+        None => call.await,
+    }
+}
+
+/// Fuel consumed by the current request.
+pub fn fuel_used(store: &Store<RequestCtx>) -> u64 {
+    store.fuel_consumed().unwrap_or_default() - store.data().limiter.fuel_baseline
+}
+
+/// Largest size the store's linear memory has reached, in bytes.
+pub fn memory_peak(store: &Store<RequestCtx>) -> usize {
+    store.data().limiter.memory_peak
+}
+
+/// Determine whether a failed guest call was caused by the request exceeding
+/// its budget.
+pub fn exceeded(store: &Store<RequestCtx>, error: &anyhow::Error) -> Option<LimitExceeded> {
+    if let Some(exceeded) = error.downcast_ref::<LimitExceeded>() {
+        return Some(*exceeded);
+    }
+    match error.downcast_ref::<Trap>()? {
+        Trap::OutOfFuel => Some(LimitExceeded::Fuel),
+        // A guest that cannot allocate aborts, which traps as unreachable
+        // code. Any other trap is its own fault, even after a refused grow.
+        Trap::UnreachableCodeReached if store.data().limiter.memory_exceeded => {
+            Some(LimitExceeded::Memory)
+        }
+        _ => None,
+    }
+}
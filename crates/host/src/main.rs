@@ -1,223 +1,102 @@
 use anyhow::anyhow;
 use clap::Parser;
-use cli::Args;
-use config::Config;
+use cli::{Args, Command, LogFormat};
 use ctx::RequestCtx;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use service::Service;
-use std::collections::HashMap;
-use std::convert::Infallible;
-use std::net::SocketAddr;
+use runtime::Runtime;
+use state::{SharedState, WasmState};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
 
-use wasmtime::component::Linker;
-use wasmtime::{Engine, Store};
-
-use apogee_sdk::http::imports::{HeaderParam, HttpComponent, Method};
-use apogee_sdk::http::imports::{HeaderResult, Request as WasmRequest, Response as WasmResponse, Version};
-
+mod access_log;
+mod admin;
+mod body;
 mod cli;
+mod compile;
 mod config;
 mod ctx;
+mod error;
 mod filesystem;
+mod handler;
+mod limits;
+mod listen;
+mod metrics;
+mod pool;
+mod reload;
+mod routes;
+mod runtime;
+mod server;
 mod service;
-
-struct WasmState {
-    config: Config,
-    services: HashMap<String, Box<Service>>,
-    engine: Engine,
-}
-
-fn init_wasmtime() -> anyhow::Result<Engine> {
-    let mut config = wasmtime::Config::new();
-    config.wasm_component_model(true);
-
-    let engine = Engine::new(&config)?;
-    Ok(engine)
-}
+mod shutdown;
+mod state;
+mod tls;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    init_tracing(args.log_format);
+    if let Some(Command::Precompile { inputs }) = &args.command {
+        for output in compile::precompile(inputs)? {
+            println!("{}", output.display());
+        }
+        return Ok(());
+    }
 
-    // Load configuration file
     let config_path = Path::new(&args.config).canonicalize()?;
-    let config = toml::from_str::<Config>(std::fs::read_to_string(&config_path)?.as_str())?;
 
     // Initialize the Wasmtime runtime
-    let engine = init_wasmtime()?;
+    let runtime = Arc::new(Runtime::new(None)?);
 
-    // Load all defined services
+    // Load the configuration file and all defined services
     let dir = config_path
         .parent()
         .ok_or_else(|| anyhow!("Cannot open base directory"))?;
     std::env::set_current_dir(dir)?;
-
-    let mut services = HashMap::new();
-    for entry in std::fs::read_dir(".")? {
-        let entry = entry?;
-        let path = entry.path();
-        if !path.is_dir() {
-            continue;
-        }
-        if !path.join("service.toml").exists() {
-            continue;
-        }
-        let service = Service::load(path.clone(), &engine);
-
-        if let Ok(service) = service {
-            services.insert(service.name.clone(), Box::new(service));
-        } else {
-            eprintln!(
-                "Error loading service in {}: {}",
-                path.display(),
-                service.err().unwrap()
-            );
-        }
+    // Loading compiles and instantiates every component, so keep it off the
+    // async workers
+    let state = {
+        let (config_path, runtime) = (config_path.clone(), runtime.clone());
+        tokio::task::spawn_blocking(move || WasmState::load(&config_path, &runtime, None))
+            .await??
+    };
+
+    // Command line listen addresses take precedence over the config file
+    let listen = if args.listen.is_empty() {
+        state.config.listen.clone()
+    } else {
+        args.listen
+    };
+    let tls = state.config.tls.clone();
+    let admin = state.config.admin.clone();
+
+    // Create a `SharedState` instance that will be shared across all threads,
+    // and keep it up to date with changes on disk
+    let state = Arc::new(SharedState::new(config_path, runtime, state)?);
+    reload::spawn(state.clone())?;
+
+    // Serve on every address until one of the listeners fails or the process
+    // is asked to terminate
+    let server = server::serve_all(listen, tls.as_ref(), admin.as_ref(), state.clone());
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => return result,
+        result = shutdown::signal() => result?,
     }
 
-    // Create a `WasmState` instance that will be shared across all threads
-    let state = Arc::new(WasmState {
-        config,
-        services,
-        engine,
-    });
-
-    // Create a `make_service_fn` closure that returns a `Service` instance
-    // for each incoming connection
-    let make_svc = make_service_fn(|_conn| {
-        // Create a clone of the `state` variable so we can use it inside the closure
-        let state = state.clone();
-
-        // Return a `service_fn` closure that takes a `Request<Body>` and
-        // returns a `Future` representing the response to the request
-        let svc = service_fn(move |req: Request<Body>| {
-            // Create a clone of the `state_for_closure` variable so we can
-            // use it inside the closure
-            let state_for_closure = state.clone();
-            async move {
-                // Route the request to the appropriate service
-                let service = state_for_closure
-                    .config
-                    .route(req.uri().to_string())
-                    .and_then(|service| state_for_closure.services.get(&service.name));
-
-                if service.is_none() {
-                    return Ok::<_, Infallible>(
-                        Response::builder()
-                            .status(404)
-                            .body(Body::from("Not Found"))
-                            .unwrap(),
-                    );
-                }
-
-                // Construct a `RequestCtx` instance for the WASM execution
-                let service = service.unwrap();
-                let ctx = service.construct_ctx();
-
-                if ctx.is_err() {
-                    return Ok::<_, Infallible>(
-                        Response::builder()
-                            .status(500)
-                            .body(Body::from("Internal Server Error"))
-                            .unwrap(),
-                    );
-                }
+    let drain_timeout = Duration::from_millis(state.current().config.drain_timeout_ms);
+    state.shutdown.drain(drain_timeout).await;
 
-                // Destructure the request parts and body
-                let (parts, body) = req.into_parts();
-
-                // Create a new `Store` and `Linker` for the WASI module
-                // TODO: Set preopened directories from a config file
-                let mut store = Store::new(&state_for_closure.engine, ctx.unwrap());
-
-                let mut linker = Linker::new(&state_for_closure.engine);
-
-                // Add the WASI module to the linker
-                wasmtime_wasi_host::add_to_linker(&mut linker, |cx: &mut RequestCtx| &mut cx.wasi)
-                    .unwrap();
-
-                // Add custom SDK filesystem module
-                filesystem::add_to_linker(&mut linker, |cx: &mut RequestCtx| cx).unwrap();
-
-                // Instantiate the HTTP component
-                let (component, _instance) =
-                    HttpComponent::instantiate(&mut store, &service.component, &linker).unwrap();
-
-                // Return a `Future` that handles the request and produces the response
-                // Convert the `Method` and `Version` from their raw
-                // representation to their corresponding structs
-                let method = Method::try_from(parts.method.clone()).unwrap();
-                let version = Version::try_from(parts.version).unwrap();
-
-                // Get the request URI as a string
-                let uri = parts.uri.to_string();
-
-                // Convert the request headers to a vector of `HeaderParam`
-                let headers: Vec<HeaderParam> = parts
-                    .headers
-                    .iter()
-                    .map(|(key, value)| HeaderParam {
-                        key: key.as_str().as_bytes(),
-                        value: value.as_bytes(),
-                    })
-                    .collect();
-
-                // Grab the request body
-                let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
-
-                // Create a `WasmRequest` from the request parts
-                let req = WasmRequest {
-                    version,
-                    method,
-                    uri: uri.as_str(),
-                    headers: headers.as_slice(),
-                    body: body.as_slice(),
-                };
-
-                // Call the `handle_http_request` method on the `Http`
-                // instance, and handle any errors that occur
-                let res = component
-                    .handle_http_request(&mut store, req)
-                    .unwrap_or_else(|e| Err(format!("Error calling wasm handler: {e}")));
-
-                // Match the result of calling handle_http_request on the Http instance, handling any errors that occur
-                let res = match res {
-                    Ok(res) => res,
-                    Err(e) => WasmResponse {
-                        status: 500,
-                        headers: vec![HeaderResult {
-                            key: b"Content-Type".to_vec(),
-                            value: b"text/plain".to_vec(),
-                        }],
-                        version: Version::HttpV11,
-                        body: e.as_bytes().to_vec(),
-                    },
-                };
-
-                // Convert the WasmResponse to a Response and handle any errors that occur
-                let body = res.body.clone();
-                Ok::<_, Infallible>(
-                    res.response_builder()
-                        .body(Body::from(String::from_utf8_lossy(&body).to_string()))
-                        .unwrap(),
-                )
-            }
-        });
-
-        async move { Ok::<_, Infallible>(svc) }
-    });
-
-    // We'll bind to 127.0.0.1:3000
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let server = Server::bind(&addr).serve(make_svc);
+    Ok(())
+}
 
-    // Run this server for... forever!
-    if let Err(e) = server.await {
-        eprintln!("server error: {e}");
+fn init_tracing(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
     }
-
-    Ok(())
 }
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// Request metrics per service, kept across reloads so that the backends of a
+/// split route can be compared.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    services: RwLock<HashMap<String, Arc<ServiceMetrics>>>,
+    /// Requests by status class per backend of each split route, keyed by
+    /// route and backend
+    backends: RwLock<HashMap<(String, String), Arc<StatusCounts>>>,
+}
+
+/// Requests by status class, 1xx to 5xx.
+type StatusCounts = [AtomicU64; 5];
+
+fn status_class(status: StatusCode) -> usize {
+    (status.as_u16() / 100).clamp(1, 5) as usize - 1
+}
+
+#[derive(Debug, Default)]
+pub struct ServiceMetrics {
+    pub requests: StatusCounts,
+    /// Total time spent handling requests
+    pub duration: Histogram,
+    /// Time spent obtaining an instance of the component
+    pub instantiate: Histogram,
+    /// Time spent running the guest
+    pub execution: Histogram,
+    /// Guest calls that trapped, including those that ran out of fuel or
+    /// memory
+    pub traps: AtomicU64,
+    pub fuel_consumed: AtomicU64,
+    /// Largest linear memory of any instance, in bytes
+    pub memory_high_water: AtomicU64,
+}
+
+/// What a single request cost, as measured by the handler.
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    pub instantiate: Option<Duration>,
+    pub execution: Option<Duration>,
+    pub trapped: bool,
+    pub fuel_consumed: u64,
+    pub memory_peak: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, service: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{service=\"{service}\",le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{service=\"{service}\",le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{service=\"{service}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{service=\"{service}\"}} {count}");
+    }
+}
+
+impl Metrics {
+    fn service(&self, name: &str) -> Arc<ServiceMetrics> {
+        if let Some(service) = self.services.read().unwrap().get(name) {
+            return service.clone();
+        }
+        self.services
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Record a request handled by the service `name`.
+    pub fn record(
+        &self,
+        name: &str,
+        status: StatusCode,
+        elapsed: Duration,
+        stats: &RequestStats,
+    ) {
+        let service = self.service(name);
+        service.requests[status_class(status)].fetch_add(1, Ordering::Relaxed);
+        service.duration.observe(elapsed);
+        if let Some(instantiate) = stats.instantiate {
+            service.instantiate.observe(instantiate);
+        }
+        if let Some(execution) = stats.execution {
+            service.execution.observe(execution);
+        }
+        if stats.trapped {
+            service.traps.fetch_add(1, Ordering::Relaxed);
+        }
+        service
+            .fuel_consumed
+            .fetch_add(stats.fuel_consumed, Ordering::Relaxed);
+        service
+            .memory_high_water
+            .fetch_max(stats.memory_peak as u64, Ordering::Relaxed);
+    }
+
+    /// Record a request that the split route `route` sent to `backend`.
+    pub fn record_backend(&self, route: &str, backend: &str, status: StatusCode) {
+        let key = (route.to_string(), backend.to_string());
+        let existing = self.backends.read().unwrap().get(&key).cloned();
+        let requests = match existing {
+            Some(requests) => requests,
+            None => self.backends.write().unwrap().entry(key).or_default().clone(),
+        };
+        requests[status_class(status)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts of every backend of a split route that has been sent a
+    /// request, by route and backend.
+    fn backends(&self) -> Vec<((String, String), Arc<StatusCounts>)> {
+        let mut backends = self
+            .backends
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, requests)| (key.clone(), requests.clone()))
+            .collect::<Vec<_>>();
+        backends.sort_by(|(a, _), (b, _)| a.cmp(b));
+        backends
+    }
+
+    /// The metrics of every service that has handled a request, by name.
+    fn services(&self) -> Vec<(String, Arc<ServiceMetrics>)> {
+        let mut services = self
+            .services
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, service)| (name.clone(), service.clone()))
+            .collect::<Vec<_>>();
+        services.sort_by(|(a, _), (b, _)| a.cmp(b));
+        services
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let services = self
+            .services()
+            .into_iter()
+            .map(|(name, service)| (escape_label(&name), service))
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "apogee_requests_total",
+            "counter",
+            "Requests handled, by status class.",
+        );
+        for (name, service) in &services {
+            for (class, requests) in service.requests.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "apogee_requests_total{{service=\"{name}\",status=\"{}xx\"}} {}",
+                    class + 1,
+                    requests.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "apogee_backend_requests_total",
+            "counter",
+            "Requests sent to each backend of a split route, by status class.",
+        );
+        for ((route, backend), requests) in self.backends() {
+            let (route, backend) = (escape_label(&route), escape_label(&backend));
+            for (class, requests) in requests.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "apogee_backend_requests_total{{route=\"{route}\",backend=\"{backend}\",\
+                     status=\"{}xx\"}} {}",
+                    class + 1,
+                    requests.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let histograms: [(&str, &str, fn(&ServiceMetrics) -> &Histogram); 3] = [
+            (
+                "apogee_request_duration_seconds",
+                "Time spent handling requests.",
+                |service| &service.duration,
+            ),
+            (
+                "apogee_instantiate_duration_seconds",
+                "Time spent obtaining an instance of the component.",
+                |service| &service.instantiate,
+            ),
+            (
+                "apogee_guest_duration_seconds",
+                "Time spent running the guest.",
+                |service| &service.execution,
+            ),
+        ];
+        for (metric, help, histogram) in histograms {
+            header(&mut out, metric, "histogram", help);
+            for (name, service) in &services {
+                histogram(service).render(&mut out, metric, name);
+            }
+        }
+
+        let counters: [(&str, &str, &str, fn(&ServiceMetrics) -> &AtomicU64); 3] = [
+            (
+                "apogee_guest_traps_total",
+                "counter",
+                "Guest calls that trapped.",
+                |service| &service.traps,
+            ),
+            (
+                "apogee_fuel_consumed_total",
+                "counter",
+                "Fuel consumed by guests.",
+                |service| &service.fuel_consumed,
+            ),
+            (
+                "apogee_memory_high_water_bytes",
+                "gauge",
+                "Largest linear memory of any instance.",
+                |service| &service.memory_high_water,
+            ),
+        ];
+        for (metric, kind, help, value) in counters {
+            header(&mut out, metric, kind, help);
+            for (name, service) in &services {
+                let value = value(service).load(Ordering::Relaxed);
+                let _ = writeln!(out, "{metric}{{service=\"{name}\"}} {value}");
+            }
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {metric} {help}");
+    let _ = writeln!(out, "# TYPE {metric} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+
+use apogee_sdk::http::imports::HttpComponent;
+use serde::{Deserialize, Serialize};
+use wasmtime::Store;
+
+use crate::ctx::RequestCtx;
+
+/// Opt-in pooling for latency-sensitive services, as declared in
+/// `service.toml`:
+///
+/// ```toml
+/// [pooling]
+/// instance_count = 64
+/// warm_instances = 8
+/// max_reuse = 1000
+/// ```
+///
+/// Warm instances keep the state a guest left behind between requests;
+/// `max_reuse` bounds how long that state lives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolingConfig {
+    /// Number of core instance slots reserved by the pooling allocator. A
+    /// single component instantiation may use several of them.
+    #[serde(default = "default_instance_count")]
+    pub instance_count: u32,
+    /// Maximum number of linear memory pages of each pooled instance.
+    pub instance_memory_pages: Option<u64>,
+    /// Number of instantiated components kept warm between requests. Zero
+    /// disables instance reuse.
+    #[serde(default)]
+    pub warm_instances: usize,
+    /// Number of requests a warm instance serves before it is discarded,
+    /// unlimited if absent.
+    pub max_reuse: Option<u32>,
+}
+
+fn default_instance_count() -> u32 {
+    1000
+}
+
+/// An instantiated HTTP component together with the store that owns it.
+pub struct Instance {
+    pub store: Store<RequestCtx>,
+    pub component: HttpComponent,
+    uses: u32,
+}
+
+impl Instance {
+    pub fn new(store: Store<RequestCtx>, component: HttpComponent) -> Self {
+        Self {
+            store,
+            component,
+            uses: 0,
+        }
+    }
+}
+
+/// A bounded set of idle instances belonging to a single service.
+pub struct InstancePool {
+    idle: Mutex<Vec<Instance>>,
+    size: usize,
+    max_reuse: Option<u32>,
+}
+
+impl InstancePool {
+    pub fn new(config: &PoolingConfig) -> Self {
+        Self {
+            idle: Mutex::new(Vec::with_capacity(config.warm_instances)),
+            size: config.warm_instances,
+            max_reuse: config.max_reuse,
+        }
+    }
+
+    pub fn take(&self) -> Option<Instance> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    /// Return an instance that completed a request successfully. It is
+    /// dropped if it has reached its reuse limit or the pool is full.
+    pub fn put(&self, mut instance: Instance) {
+        instance.uses += 1;
+        if self.max_reuse.map_or(false, |max_reuse| instance.uses >= max_reuse) {
+            return;
+        }
+        instance.store.data_mut().release_bodies();
+        self.add(instance);
+    }
+
+    /// Keep an instance that has not served a request yet, if there is room.
+    pub fn add(&self, instance: Instance) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.size {
+            idle.push(instance);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::state::{self, SharedState, WasmState};
+
+/// How often the configuration and service files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload the configuration and services whenever their files change, the
+/// process receives `SIGHUP` or the admin API asks for it. Listen addresses
+/// and TLS settings are only read at startup.
+pub fn spawn(state: Arc<SharedState>) -> anyhow::Result<()> {
+    let mut hangup = Hangup::new()?;
+
+    tokio::spawn(async move {
+        let config_path = state.config_path.clone();
+        let mut last_seen = fingerprint(&config_path, state.current()).await;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let current = fingerprint(&config_path, state.current()).await;
+                    if current == last_seen {
+                        continue;
+                    }
+                    last_seen = current;
+                }
+                _ = hangup.recv() => {
+                    tracing::info!("Received SIGHUP, reloading");
+                }
+                _ = state.reload.notified() => {
+                    last_seen = fingerprint(&config_path, state.current()).await;
+                }
+            }
+
+            // Compiling components is CPU heavy, so keep it off the workers
+            let (config_path, runtime, previous) =
+                (config_path.clone(), state.runtime.clone(), state.current());
+            let reloaded = tokio::task::spawn_blocking(move || {
+                WasmState::load(&config_path, &runtime, Some(&previous))
+            })
+            .await;
+            match reloaded {
+                Ok(Ok(reloaded)) => {
+                    tracing::info!("Reloaded {} services", reloaded.services.len());
+                    state.replace(reloaded);
+                    // The new configuration may list other service directories
+                    last_seen = fingerprint(&config_path, state.current()).await;
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Error reloading configuration, keeping previous: {e}")
+                }
+                Err(e) => tracing::error!("Error reloading configuration, keeping previous: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// Modification times of the configuration file, of the files in every
+/// service directory that `state` loads services from, and of the components
+/// of loaded services wherever they are, gathered on a blocking thread.
+async fn fingerprint(config_path: &Path, state: Arc<WasmState>) -> Fingerprint {
+    let config_path = config_path.to_path_buf();
+    tokio::task::spawn_blocking(move || scan(&config_path, &state))
+        .await
+        .unwrap_or_default()
+}
+
+fn scan(config_path: &Path, state: &WasmState) -> Fingerprint {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut files = vec![(config_path.to_path_buf(), modified(config_path))];
+    for dir in state::service_dirs(&state.config).unwrap_or_default() {
+        let entries = std::fs::read_dir(&dir).into_iter().flatten().flatten();
+        for path in entries.map(|entry| entry.path()).filter(|path| path.is_file()) {
+            let modified = modified(&path);
+            files.push((path, modified));
+        }
+    }
+    // A component may live outside its service directory
+    for service in state.services.values() {
+        let wasm = service.directory.join(&service.config.wasm);
+        let modified = modified(&wasm);
+        files.push((wasm, modified));
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Resolves every time the process receives `SIGHUP`, or never on platforms
+/// without signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
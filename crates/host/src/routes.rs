@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::anyhow;
+use hyper::Uri;
+use patricia_tree::PatriciaMap;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ServiceDescription;
+
+/// The routing table, as declared in `config.toml`:
+///
+/// ```toml
+/// [routes."/"]
+/// name = "site"
+///
+/// [routes."/users/{id}/posts/{post}"]
+/// name = "posts"
+/// methods = ["GET", "DELETE"]
+///
+/// [routes."/static/*rest"]
+/// name = "files"
+/// ```
+///
+/// Literal routes match any path below their prefix and are looked up in a
+/// patricia tree. Routes containing `{name}` segments or a trailing `*name`
+/// segment are patterns; a `{name}` segment matches exactly one non-empty
+/// path segment and `*name` matches the rest of the path.
+///
+/// When several routes match, the most specific one wins: the route with the
+/// most literal segments, then a pattern without a wildcard over one with a
+/// wildcard or a literal prefix, then the route with the most segments.
+/// Routes whose `methods` do not include the request's method are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    literal: PatriciaMap<ServiceDescription>,
+    patterns: Vec<RoutePattern>,
+}
+
+/// The route a request was matched to.
+#[derive(Debug, Clone)]
+pub struct Route<'a> {
+    /// The route's key in the configuration
+    pub pattern: &'a str,
+    /// The part of the path matched by the route
+    pub prefix: &'a str,
+    /// The rest of the path following the prefix
+    pub remainder: &'a str,
+    /// Values of the pattern's named segments
+    pub params: Vec<(&'a str, &'a str)>,
+    pub service: &'a ServiceDescription,
+}
+
+/// Outcome of looking up a request in the routing table.
+#[derive(Debug)]
+pub enum RouteMatch<'a> {
+    Found(Route<'a>),
+    /// Some route matches the path, but none allows the method. Holds the
+    /// methods that the matching routes allow, in upper case.
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+impl Route<'_> {
+    /// The URI passed to the guest, after applying the route's `rewrite`,
+    /// `strip_prefix` and `add_prefix` options. A `rewrite` replaces the
+    /// whole path, so `strip_prefix` is ignored alongside it. The query string
+    /// is always kept.
+    pub fn guest_uri(&self, uri: &Uri) -> String {
+        let service = self.service;
+        let path = match &service.rewrite {
+            Some(template) => self
+                .params
+                .iter()
+                .fold(template.clone(), |path, &(name, value)| {
+                    path.replace(&format!("{{{name}}}"), value)
+                }),
+            None if service.strip_prefix => self.remainder.to_string(),
+            None if service.add_prefix.is_some() => uri.path().to_string(),
+            // Pass the URI through untouched
+            None => return uri.to_string(),
+        };
+        let path = match &service.add_prefix {
+            Some(prefix) => format!(
+                "{}/{}",
+                prefix.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            ),
+            None if !path.starts_with('/') => format!("/{path}"),
+            None => path,
+        };
+        match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        }
+    }
+}
+
+impl Routes {
+    pub fn route<'a>(&'a self, method: &str, path: &'a str) -> RouteMatch<'a> {
+        let mut allowed = Vec::new();
+
+        let mut best = self.route_literal(method, path, &mut allowed);
+        for pattern in &self.patterns {
+            if best
+                .as_ref()
+                .map_or(false, |(specificity, _)| *specificity >= pattern.specificity)
+            {
+                // Patterns are sorted by specificity, none of the rest can win
+                break;
+            }
+            if let Some(route) = pattern.matches(path) {
+                if pattern.service.allows(method) {
+                    best = Some((pattern.specificity, route));
+                    break;
+                }
+                allowed.extend(&pattern.service.methods);
+            }
+        }
+
+        match best {
+            Some((_, route)) => RouteMatch::Found(route),
+            // Routes that allow any method always match, so a route that
+            // matched the path has contributed its methods
+            None if !allowed.is_empty() => {
+                let mut allowed = allowed
+                    .into_iter()
+                    .map(|method| method.to_ascii_uppercase())
+                    .collect::<Vec<_>>();
+                allowed.sort();
+                allowed.dedup();
+                RouteMatch::MethodNotAllowed(allowed)
+            }
+            None => RouteMatch::NotFound,
+        }
+    }
+
+    /// Names of every service that requests are routed to.
+    pub fn service_names(&self) -> impl Iterator<Item = &str> {
+        self.literal
+            .iter()
+            .map(|(_, service)| service)
+            .chain(self.patterns.iter().map(|pattern| &pattern.service))
+            .flat_map(ServiceDescription::service_names)
+    }
+
+    /// Find the longest literal prefix of `path` whose route allows `method`,
+    /// adding the methods of matching routes that do not to `allowed`.
+    fn route_literal<'a>(
+        &'a self,
+        method: &str,
+        path: &'a str,
+        allowed: &mut Vec<&'a String>,
+    ) -> Option<(Specificity, Route<'a>)> {
+        let mut end = path.len();
+        loop {
+            let (prefix, service) = self.literal.get_longest_common_prefix(&path[..end])?;
+            let prefix = std::str::from_utf8(prefix).unwrap();
+            let remainder = &path[prefix.len()..];
+
+            // Only match on segment boundaries
+            if prefix.ends_with('/') || remainder.starts_with('/') || remainder.is_empty() {
+                if service.allows(method) {
+                    let segments = segments(prefix).count();
+                    let route = Route {
+                        pattern: match prefix {
+                            "" => "/",
+                            prefix => prefix,
+                        },
+                        prefix,
+                        remainder,
+                        params: Vec::new(),
+                        service,
+                    };
+                    return Some(((segments, false, segments), route));
+                }
+                allowed.extend(&service.methods);
+            }
+
+            // Retry with the next shorter prefix
+            end = prefix.char_indices().next_back()?.0;
+        }
+    }
+
+    fn insert(&mut self, key: &str, service: ServiceDescription) -> anyhow::Result<()> {
+        service
+            .validate()
+            .map_err(|e| anyhow!("Invalid route {key}: {e}"))?;
+        if key.contains(['{', '*']) {
+            let pattern = RoutePattern::parse(key, service)?;
+            let index = self
+                .patterns
+                .partition_point(|other| other.specificity >= pattern.specificity);
+            self.patterns.insert(index, pattern);
+        } else {
+            self.literal.insert(key.trim_end_matches('/'), service);
+        }
+        Ok(())
+    }
+}
+
+/// Literal segments, exact (no wildcard), total segments
+type Specificity = (usize, bool, usize);
+
+#[derive(Debug, Clone)]
+struct RoutePattern {
+    source: String,
+    segments: Vec<Segment>,
+    specificity: Specificity,
+    service: ServiceDescription,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl RoutePattern {
+    fn parse(source: &str, service: ServiceDescription) -> anyhow::Result<Self> {
+        let mut parsed = Vec::new();
+        let mut names = Vec::new();
+        let mut segments = segments(source).peekable();
+        while let Some(segment) = segments.next() {
+            let segment = if let Some(name) = segment.strip_prefix('*') {
+                if segments.peek().is_some() {
+                    return Err(anyhow!("Wildcard must be the last segment of route {source}"));
+                }
+                names.push(name);
+                Segment::Wildcard(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                names.push(name);
+                Segment::Param(name.to_string())
+            } else if segment.contains(['{', '}', '*']) {
+                return Err(anyhow!("Invalid segment {segment:?} in route {source}"));
+            } else {
+                Segment::Literal(segment.to_string())
+            };
+            parsed.push(segment);
+        }
+        for (index, name) in names.iter().enumerate() {
+            if name.is_empty() {
+                return Err(anyhow!("Unnamed parameter in route {source}"));
+            }
+            if names[..index].contains(name) {
+                return Err(anyhow!("Duplicate parameter {name} in route {source}"));
+            }
+        }
+
+        let literals = parsed
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count();
+        let exact = !matches!(parsed.last(), Some(Segment::Wildcard(_)));
+        Ok(Self {
+            source: source.to_string(),
+            specificity: (literals, exact, parsed.len()),
+            segments: parsed,
+            service,
+        })
+    }
+
+    fn matches<'a>(&'a self, path: &'a str) -> Option<Route<'a>> {
+        let mut params = Vec::new();
+        let trimmed = path.trim_end_matches('/');
+        let mut rest = trimmed;
+        for segment in &self.segments {
+            rest = rest.strip_prefix('/').unwrap_or(rest);
+            if let Segment::Wildcard(name) = segment {
+                params.push((name.as_str(), rest));
+                let offset = trimmed.len() - rest.len();
+                let prefix = path[..offset].trim_end_matches('/');
+                return Some(Route {
+                    pattern: &self.source,
+                    prefix,
+                    remainder: &path[prefix.len()..],
+                    params,
+                    service: &self.service,
+                });
+            }
+
+            let value = rest.split('/').next().unwrap_or_default();
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Param(name) if !value.is_empty() => params.push((name.as_str(), value)),
+                _ => return None,
+            }
+            rest = &rest[value.len()..];
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Route {
+            pattern: &self.source,
+            prefix: trimmed,
+            remainder: &path[trimmed.len()..],
+            params,
+            service: &self.service,
+        })
+    }
+}
+
+/// The non-empty segments of a path.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+impl Serialize for Routes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let literal = self
+            .literal
+            .iter()
+            .map(|(key, service)| (String::from_utf8_lossy(&key).into_owned(), service));
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|pattern| (pattern.source.clone(), &pattern.service));
+        literal.chain(patterns).collect::<HashMap<_, _>>().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Routes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RoutesVisitor;
+        impl<'de> Visitor<'de> for RoutesVisitor {
+            type Value = Routes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of routes to services")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut routes = Routes::default();
+                while let Some((key, value)) = map.next_entry::<String, ServiceDescription>()? {
+                    routes.insert(&key, value).map_err(serde::de::Error::custom)?;
+                }
+                Ok(routes)
+            }
+        }
+
+        deserializer.deserialize_map(RoutesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(toml: &str) -> Routes {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// The name of the service `path` is routed to, if any.
+    fn target<'a>(routes: &'a Routes, method: &str, path: &'a str) -> Option<&'a str> {
+        match routes.route(method, path) {
+            RouteMatch::Found(route) => route.service.name.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn found<'a>(routes: &'a Routes, path: &'a str) -> Route<'a> {
+        match routes.route("GET", path) {
+            RouteMatch::Found(route) => route,
+            other => panic!("{path} did not match: {other:?}"),
+        }
+    }
+
+    /// The URI passed to the guest for a `GET` of `uri`.
+    fn guest_uri(routes: &Routes, uri: &str) -> String {
+        let uri = uri.parse::<Uri>().unwrap();
+        found(routes, uri.path()).guest_uri(&uri)
+    }
+
+    #[test]
+    fn pattern_beats_shorter_literal_prefix() {
+        let routes = routes(
+            r#"
+            "/users" = { name = "list" }
+            "/users/{id}" = { name = "user" }
+            "/users/me" = { name = "me" }
+            "#,
+        );
+        assert_eq!(target(&routes, "GET", "/users"), Some("list"));
+        assert_eq!(target(&routes, "GET", "/users/42"), Some("user"));
+        // More literal segments win over a parameter
+        assert_eq!(target(&routes, "GET", "/users/me"), Some("me"));
+        // The pattern matches exactly two segments, the literal any below it
+        assert_eq!(target(&routes, "GET", "/users/42/posts"), Some("list"));
+    }
+
+    #[test]
+    fn literal_prefixes_match_on_segment_boundaries() {
+        let routes = routes(r#""/api" = { name = "api" }"#);
+        assert_eq!(target(&routes, "GET", "/api"), Some("api"));
+        assert_eq!(target(&routes, "GET", "/api/"), Some("api"));
+        assert_eq!(target(&routes, "GET", "/apiary"), None);
+
+        let route = found(&routes, "/api/v1/items");
+        assert_eq!(route.prefix, "/api");
+        assert_eq!(route.remainder, "/v1/items");
+    }
+
+    #[test]
+    fn wildcard_captures_the_rest_of_the_path() {
+        let routes = routes(
+            r#"
+            "/static/*rest" = { name = "files" }
+            "/static/{file}" = { name = "file" }
+            "#,
+        );
+        let route = found(&routes, "/static/css/site.css");
+        assert_eq!(route.service.name.as_deref(), Some("files"));
+        assert_eq!(route.params, vec![("rest", "css/site.css")]);
+        assert_eq!(route.prefix, "/static");
+        // A pattern without a wildcard is more specific
+        assert_eq!(target(&routes, "GET", "/static/site.css"), Some("file"));
+    }
+
+    #[test]
+    fn method_mismatch_falls_through_to_other_routes() {
+        let routes = routes(
+            r#"
+            "/" = { name = "site" }
+            "/api" = { name = "writer", methods = ["POST", "put"] }
+            "/items/{id}" = { name = "items", methods = ["DELETE"] }
+            "#,
+        );
+        assert_eq!(target(&routes, "POST", "/api/x"), Some("writer"));
+        assert_eq!(target(&routes, "GET", "/api/x"), Some("site"));
+        // Literal prefixes still match below a pattern that refuses
+        assert_eq!(target(&routes, "GET", "/items/1"), Some("site"));
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allowed_methods() {
+        let routes = routes(
+            r#"
+            "/api" = { name = "writer", methods = ["POST", "put"] }
+            "/api/{id}" = { name = "reader", methods = ["GET", "post"] }
+            "#,
+        );
+        match routes.route("DELETE", "/api/1") {
+            RouteMatch::MethodNotAllowed(allowed) => {
+                assert_eq!(allowed, vec!["GET", "POST", "PUT"]);
+            }
+            other => panic!("Expected 405, got {other:?}"),
+        }
+        assert!(matches!(routes.route("GET", "/other"), RouteMatch::NotFound));
+    }
+
+    #[test]
+    fn uri_is_passed_through_without_options() {
+        let routes = routes(r#""/api" = { name = "api" }"#);
+        assert_eq!(guest_uri(&routes, "/api/items?a=b"), "/api/items?a=b");
+    }
+
+    #[test]
+    fn strip_prefix_removes_the_matched_prefix() {
+        let routes = routes(r#""/api" = { name = "api", strip_prefix = true }"#);
+        assert_eq!(guest_uri(&routes, "/api/v1/items?a=b"), "/v1/items?a=b");
+        assert_eq!(guest_uri(&routes, "/api"), "/");
+    }
+
+    #[test]
+    fn rewrite_fills_in_params_and_overrides_strip_prefix() {
+        let routes = routes(
+            r#"
+            "/users/{id}" = { name = "users", rewrite = "/profiles/{id}/view" }
+            "/posts/{id}" = { name = "posts", rewrite = "/p/{id}", strip_prefix = true }
+            "#,
+        );
+        assert_eq!(guest_uri(&routes, "/users/42?full"), "/profiles/42/view?full");
+        assert_eq!(guest_uri(&routes, "/posts/7"), "/p/7");
+    }
+
+    #[test]
+    fn add_prefix_applies_after_stripping_or_rewriting() {
+        let routes = routes(
+            r#"
+            "/api" = { name = "api", add_prefix = "/v2/" }
+            "/old" = { name = "old", strip_prefix = true, add_prefix = "/new" }
+            "/items/{id}" = { name = "items", rewrite = "item/{id}", add_prefix = "/v1" }
+            "#,
+        );
+        assert_eq!(guest_uri(&routes, "/api/items?a=b"), "/v2/api/items?a=b");
+        assert_eq!(guest_uri(&routes, "/old/page"), "/new/page");
+        assert_eq!(guest_uri(&routes, "/items/3?x"), "/v1/item/3?x");
+    }
+}
@@ -0,0 +1,77 @@
+use wasmtime::component::Linker;
+use tokio::task::AbortHandle;
+use wasmtime::{Engine, InstanceAllocationStrategy, PoolingAllocationConfig};
+
+use crate::ctx::RequestCtx;
+use crate::pool::PoolingConfig;
+use crate::{body, filesystem, limits};
+
+/// A Wasmtime engine together with a linker providing every host interface.
+pub struct Runtime {
+    pub engine: Engine,
+    pub linker: Linker<RequestCtx>,
+    /// Identifies the wasmtime version and engine settings that compiled code
+    /// depends on
+    pub fingerprint: Vec<u8>,
+    epoch_ticker: AbortHandle,
+}
+
+impl Runtime {
+    /// Create a runtime, optionally backed by wasmtime's pooling instance
+    /// allocator.
+    pub fn new(pooling: Option<&PoolingConfig>) -> anyhow::Result<Self> {
+        let engine = init_wasmtime(pooling)?;
+        let linker = init_linker(&engine)?;
+        // Compiled code embeds the version and settings of the engine that
+        // produced it, so an empty module captures all of them
+        let fingerprint = engine.precompile_module(b"\0asm\x01\0\0\0")?;
+        Ok(Self {
+            epoch_ticker: limits::spawn_epoch_ticker(engine.clone()),
+            engine,
+            linker,
+            fingerprint,
+        })
+    }
+}
+
+impl Drop for Runtime {
+    /// Stop ticking, so that the engine and the memory it reserved are freed
+    /// once the last store using it is dropped.
+    fn drop(&mut self) {
+        self.epoch_ticker.abort();
+    }
+}
+
+fn init_wasmtime(pooling: Option<&PoolingConfig>) -> anyhow::Result<Engine> {
+    let mut config = wasmtime::Config::new();
+    config.wasm_component_model(true);
+    config.async_support(true);
+    limits::configure_engine(&mut config);
+
+    if let Some(pooling) = pooling {
+        let mut allocation = PoolingAllocationConfig::default();
+        allocation.instance_count(pooling.instance_count);
+        if let Some(pages) = pooling.instance_memory_pages {
+            allocation.instance_memory_pages(pages);
+        }
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(allocation));
+    }
+
+    let engine = Engine::new(&config)?;
+    Ok(engine)
+}
+
+pub(crate) fn init_linker(engine: &Engine) -> anyhow::Result<Linker<RequestCtx>> {
+    let mut linker = Linker::new(engine);
+
+    // Add the WASI module to the linker
+    wasmtime_wasi_host::add_to_linker(&mut linker, |cx: &mut RequestCtx| &mut cx.wasi)?;
+
+    // Add custom SDK filesystem module
+    filesystem::add_to_linker(&mut linker, |cx: &mut RequestCtx| cx)?;
+
+    // Add the streaming HTTP body module
+    body::add_to_linker(&mut linker, |cx: &mut RequestCtx| cx)?;
+
+    Ok(linker)
+}
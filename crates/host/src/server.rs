@@ -0,0 +1,218 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio_rustls::server::TlsStream;
+
+use crate::admin::{self, AdminConfig};
+use crate::handler;
+use crate::listen::ListenAddr;
+use crate::state::SharedState;
+use crate::tls::{self, TlsConfig, TlsIncoming};
+
+/// Serve requests on every address, returning once any listener fails.
+pub async fn serve_all(
+    listen: Vec<ListenAddr>,
+    tls: Option<&TlsConfig>,
+    admin: Option<&AdminConfig>,
+    state: Arc<SharedState>,
+) -> anyhow::Result<()> {
+    let tls_listen = tls.map(|tls| tls.listen.as_slice()).unwrap_or_default();
+    if listen.is_empty() && tls_listen.is_empty() {
+        return Err(anyhow!("No listen addresses configured"));
+    }
+
+    let mut incomings = Vec::new();
+    for addr in listen {
+        let incoming = bind(&addr)?;
+        tracing::info!("Listening on {addr}");
+        incomings.push((addr.to_string(), incoming, Role::Public));
+    }
+    if let Some(tls) = tls.filter(|tls| !tls.listen.is_empty()) {
+        let acceptor = tls::acceptor(tls)?;
+        for addr in &tls.listen {
+            let incoming = TlsIncoming::bind(*addr, acceptor.clone(), state.clone()).await?;
+            tracing::info!("Listening on {addr} (TLS)");
+            incomings.push((addr.to_string(), Incoming::Tls(incoming), Role::Public));
+        }
+    }
+    if let Some(admin) = admin {
+        let incoming = bind(&admin.listen)?;
+        tracing::info!("Admin API listening on {}", admin.listen);
+        incomings.push((admin.listen.to_string(), incoming, Role::Admin));
+    }
+
+    let mut listeners = JoinSet::new();
+    for (addr, incoming, role) in incomings {
+        let state = state.clone();
+        listeners.spawn(async move {
+            let result = match role {
+                Role::Public => serve_incoming(incoming, state, handler::handle).await,
+                Role::Admin => serve_incoming(incoming, state, admin::handle).await,
+            };
+            result.map_err(|e| anyhow!("server error on {addr}: {e}"))
+        });
+    }
+
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Which requests a listener serves.
+enum Role {
+    /// Requests routed to services
+    Public,
+    /// The host's own admin API
+    Admin,
+}
+
+enum Incoming {
+    Tcp(AddrIncoming),
+    Tls(TlsIncoming),
+    #[cfg(unix)]
+    Unix(unix::UnixIncoming),
+}
+
+fn bind(addr: &ListenAddr) -> anyhow::Result<Incoming> {
+    match addr {
+        ListenAddr::Tcp(addr) => Ok(Incoming::Tcp(AddrIncoming::bind(addr)?)),
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => Ok(Incoming::Unix(unix::UnixIncoming::bind(path)?)),
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(anyhow!("Unix sockets are not supported on this platform")),
+    }
+}
+
+/// What is known about the connection a request arrived on.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnInfo {
+    /// Address of the client, if it connected over TCP
+    pub peer_addr: Option<SocketAddr>,
+    /// `http` or `https`
+    pub scheme: &'static str,
+}
+
+/// A connection accepted by one of the listeners.
+trait Connection {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for tokio::net::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+async fn serve_incoming<H, F>(
+    incoming: Incoming,
+    state: Arc<SharedState>,
+    handler: H,
+) -> hyper::Result<()>
+where
+    H: Fn(Arc<SharedState>, ConnInfo, Request<Body>) -> F + Copy + Send + Sync + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    match incoming {
+        Incoming::Tcp(incoming) => serve(incoming, "http", state, handler).await,
+        Incoming::Tls(incoming) => serve(incoming, "https", state, handler).await,
+        #[cfg(unix)]
+        Incoming::Unix(incoming) => serve(incoming, "http", state, handler).await,
+    }
+}
+
+async fn serve<I, H, F>(
+    incoming: I,
+    scheme: &'static str,
+    state: Arc<SharedState>,
+    handler: H,
+) -> hyper::Result<()>
+where
+    I: Accept,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    H: Fn(Arc<SharedState>, ConnInfo, Request<Body>) -> F + Copy + Send + Sync + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    // Create a `make_service_fn` closure that returns a `Service` instance
+    // for each incoming connection
+    let make_svc = {
+        let state = state.clone();
+        make_service_fn(move |conn: &I::Conn| {
+            let state = state.clone();
+            let conn = ConnInfo {
+                peer_addr: conn.peer_addr(),
+                scheme,
+            };
+            let svc = service_fn(move |req| handler(state.clone(), conn, req));
+            async move { Ok::<_, Infallible>(svc) }
+        })
+    };
+    // Stop accepting connections once the host starts draining
+    Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { state.shutdown.draining().await })
+        .await
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::FileTypeExt;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use hyper::server::accept::Accept;
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub struct UnixIncoming(UnixListener);
+
+    impl UnixIncoming {
+        pub fn bind(path: &Path) -> std::io::Result<Self> {
+            // Remove a socket left behind by a previous run
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            Ok(Self(UnixListener::bind(path)?))
+        }
+    }
+
+    impl Accept for UnixIncoming {
+        type Conn = UnixStream;
+        type Error = std::io::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            self.0
+                .poll_accept(cx)
+                .map(|result| Some(result.map(|(stream, _)| stream)))
+        }
+    }
+}
@@ -1,22 +1,53 @@
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use wasmtime::{component::Component, Engine};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use wasmtime::component::{Component, InstancePre};
+use wasmtime::{Engine, Store};
 
+use apogee_sdk::http::imports::HttpComponent;
+
+use crate::compile;
 use crate::ctx::RequestCtx;
+use crate::limits::{self, ServiceLimits};
+use crate::pool::{Instance, InstancePool, PoolingConfig};
+use crate::runtime::Runtime;
+
+/// Deadline for instantiating a component while loading it, for services that
+/// declare no `timeout_ms`.
+const LOAD_TIMEOUT_MS: u64 = 10_000;
 
 pub struct Service {
+    pub engine: Engine,
+    /// The dedicated runtime of a pooled service, kept so that its engine
+    /// is ticked for as long as the service is in use
+    _pooled_runtime: Option<Runtime>,
     pub component: Component,
+    /// The entry of the compile cache holding the component, if any
+    pub cache_entry: Option<PathBuf>,
+    /// The component with its imports already resolved against the host
+    /// linker, so that each request only has to instantiate it.
+    pub instance_pre: InstancePre<RequestCtx>,
     pub name: String,
     pub directory: PathBuf,
     pub config: ServiceConfig,
+    /// Warm instances kept between requests, if enabled in `service.toml`.
+    pub pool: Option<InstancePool>,
+    /// Latest modification time of `service.toml` and the component file when
+    /// the service was loaded
+    pub modified: Option<SystemTime>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceConfig {
     pub name: String,
+    /// The component, or its `.cwasm` output from `apogee precompile`. The
+    /// admin API only accepts the component.
     pub wasm: PathBuf,
     pub filesystem: Vec<FilesystemEntry>,
+    #[serde(default)]
+    pub limits: ServiceLimits,
+    pub pooling: Option<PoolingConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,7 +57,17 @@ pub struct FilesystemEntry {
 }
 
 impl Service {
-    pub fn load(directory: PathBuf, engine: &Engine) -> anyhow::Result<Service> {
+    /// Load the service in `directory`, reusing compiled components from
+    /// `cache` if given. Precompiled components are refused unless
+    /// `allow_precompiled`, for services that did not come from the operator.
+    ///
+    /// This blocks on the async runtime, so it must run on a blocking thread.
+    pub fn load(
+        directory: PathBuf,
+        runtime: &Runtime,
+        cache: Option<&Path>,
+        allow_precompiled: bool,
+    ) -> anyhow::Result<Service> {
         let service_file = directory.join("service.toml");
         if !service_file.exists() {
             return Err(anyhow!("Service file not found"));
@@ -34,15 +75,100 @@ impl Service {
         let service_config =
             toml::from_str::<ServiceConfig>(std::fs::read_to_string(&service_file)?.as_str())?;
 
+        // Pooled services get a dedicated engine, as the allocation strategy
+        // is fixed per engine.
+        let pooled_runtime = service_config
+            .pooling
+            .as_ref()
+            .map(|pooling| Runtime::new(Some(pooling)))
+            .transpose()?;
+        let runtime = pooled_runtime.as_ref().unwrap_or(runtime);
+
         let wasm_path = directory.join(&service_config.wasm);
-        let component = Component::from_file(engine, wasm_path)?;
+        let modified = Self::files_modified(&service_file, &wasm_path);
+        let (component, cache_entry) =
+            compile::load_component(runtime, &wasm_path, cache, allow_precompiled)?;
+        let instance_pre = runtime.linker.instantiate_pre(&component).map_err(|e| {
+            anyhow!(
+                "{} does not match the interfaces the host provides: {e:#}",
+                wasm_path.display()
+            )
+        })?;
 
-        Ok(Service {
+        let pool = service_config
+            .pooling
+            .as_ref()
+            .filter(|pooling| pooling.warm_instances > 0)
+            .map(InstancePool::new);
+
+        let service = Service {
+            engine: runtime.engine.clone(),
+            _pooled_runtime: pooled_runtime,
             component,
+            cache_entry,
+            instance_pre,
             name: service_config.name.clone(),
             directory,
             config: service_config,
-        })
+            pool,
+            modified,
+        };
+
+        // Exports are only resolved on instantiation, so instantiate once now
+        // rather than failing on the first request. Start code runs here, so
+        // it is bounded by a deadline even if the service sets none. The
+        // instance is not wasted if the service keeps warm instances.
+        let limits = ServiceLimits {
+            timeout_ms: Some(service.config.limits.timeout_ms.unwrap_or(LOAD_TIMEOUT_MS)),
+            ..service.config.limits.clone()
+        };
+        let instantiate = limits::with_deadline(&limits, service.instantiate());
+        let instance = tokio::runtime::Handle::current()
+            .block_on(instantiate)
+            .map_err(|e| anyhow!("Error instantiating {}: {e:#}", wasm_path.display()))?;
+        if let Some(pool) = &service.pool {
+            pool.add(instance);
+        }
+        Ok(service)
+    }
+
+    fn files_modified(service_file: &Path, wasm_path: &Path) -> Option<SystemTime> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some(modified(service_file)?.max(modified(wasm_path)?))
+    }
+
+    /// Whether `service.toml` or the component changed since the service was
+    /// loaded.
+    pub fn is_stale(&self) -> bool {
+        let modified = Self::files_modified(
+            &self.directory.join("service.toml"),
+            &self.directory.join(&self.config.wasm),
+        );
+        modified.is_none() || modified != self.modified
+    }
+
+    /// Get an instance to handle a request, reusing a warm one if available.
+    pub async fn instantiate(&self) -> anyhow::Result<Instance> {
+        if let Some(instance) = self.pool.as_ref().and_then(InstancePool::take) {
+            return Ok(instance);
+        }
+
+        let mut store = Store::new(&self.engine, self.construct_ctx()?);
+        limits::configure_store(&mut store);
+        // Instantiation may run guest code, so it is subject to the limits too.
+        limits::apply(&mut store, &self.config.limits)?;
+        let instance = self.instance_pre.instantiate_async(&mut store).await?;
+        let component = HttpComponent::new(&mut store, &instance).map_err(|e| {
+            anyhow!("Component does not export the `http-component` interface: {e}")
+        })?;
+        Ok(Instance::new(store, component))
+    }
+
+    /// Hand back an instance that completed a request successfully.
+    pub fn release(&self, instance: Instance) {
+        if let Some(pool) = &self.pool {
+            pool.put(instance);
+        }
     }
 
     pub fn construct_ctx(&self) -> anyhow::Result<RequestCtx> {
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+/// How long interrupted requests are given to unwind before the host exits.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+
+/// Coordinates graceful shutdown: listeners stop accepting once draining
+/// begins, and guest executions still running after the drain timeout are
+/// interrupted. Guests yield on every epoch tick, so an interrupt takes
+/// effect within one tick.
+#[derive(Default)]
+pub struct Shutdown {
+    draining: Flag,
+    interrupted: Flag,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    cut_off: AtomicUsize,
+}
+
+impl Shutdown {
+    /// Register an in-flight request for as long as the guard is alive.
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// Resolves once the host starts shutting down.
+    pub async fn draining(&self) {
+        self.draining.wait().await
+    }
+
+    /// Run a guest call, aborting it if the host interrupts in-flight
+    /// requests.
+    pub async fn interruptible<T>(
+        &self,
+        call: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        tokio::select! {
+            result = call => result,
+            _ = self.interrupted.wait() => {
+                self.cut_off.fetch_add(1, Ordering::SeqCst);
+                Err(Interrupted.into())
+            }
+        }
+    }
+
+    /// Stop accepting connections and wait up to `timeout` for in-flight
+    /// requests, then interrupt whatever is still running.
+    pub async fn drain(&self, timeout: Duration) {
+        let in_flight = self.in_flight.load(Ordering::SeqCst);
+        tracing::info!("Shutting down, draining {in_flight} in-flight requests");
+        self.draining.set();
+
+        if tokio::time::timeout(timeout, self.wait_idle()).await.is_ok() {
+            tracing::info!("All in-flight requests completed");
+            return;
+        }
+
+        self.interrupted.set();
+        let _ = tokio::time::timeout(INTERRUPT_GRACE, self.wait_idle()).await;
+        tracing::warn!(
+            "Drain timeout of {timeout:?} elapsed, cut off {} requests",
+            self.cut_off.load(Ordering::SeqCst)
+        );
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Guard returned by [`Shutdown::track`].
+pub struct InFlight<'a>(&'a Shutdown);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// The error a guest call fails with when it is interrupted by shutdown.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted by host shutdown")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// A one-shot flag that can be awaited.
+#[derive(Default)]
+struct Flag {
+    set: AtomicBool,
+    notify: Notify,
+}
+
+impl Flag {
+    fn set(&self) {
+        self.set.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.set.load(Ordering::SeqCst) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Resolves when the process is asked to terminate (`SIGTERM` or Ctrl-C).
+pub async fn signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use path_clean::PathClean;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::access_log::AccessLog;
+use crate::compile;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::runtime::Runtime;
+use crate::service::Service;
+use crate::shutdown::Shutdown;
+
+/// The configuration and services that requests are routed against.
+pub struct WasmState {
+    pub config: Config,
+    pub services: HashMap<String, Arc<Service>>,
+    /// Contents of the configured error page template
+    pub error_page: Option<String>,
+    /// Services that failed to load, reported by the health endpoint
+    pub load_errors: Vec<LoadError>,
+    /// Names of services that routes refer to but that are not loaded
+    pub missing_services: Vec<String>,
+}
+
+/// A service directory that could not be loaded.
+#[derive(Debug, Serialize)]
+pub struct LoadError {
+    pub directory: PathBuf,
+    pub error: String,
+}
+
+impl WasmState {
+    /// Read the configuration file and load every service found by
+    /// `service_dirs`. Services whose files have not changed since `previous`
+    /// was loaded are reused rather than recompiled.
+    ///
+    /// Services that fail to load are recorded in `load_errors`, and their
+    /// version from `previous` is kept if there is one. In `strict` mode,
+    /// loading fails instead.
+    ///
+    /// Like `Service::load`, this must run on a blocking thread.
+    pub fn load(
+        config_path: &Path,
+        runtime: &Runtime,
+        previous: Option<&WasmState>,
+    ) -> anyhow::Result<WasmState> {
+        let config = toml::from_str::<Config>(std::fs::read_to_string(config_path)?.as_str())?;
+        let error_page = config
+            .error_page
+            .as_ref()
+            .map(std::fs::read_to_string)
+            .transpose()?;
+
+        let mut services = HashMap::new();
+        let mut load_errors = Vec::new();
+        for path in service_dirs(&config)? {
+            let loaded = previous.and_then(|previous| {
+                previous
+                    .services
+                    .values()
+                    .find(|service| service.directory == path)
+            });
+            let service = match loaded {
+                Some(service) if !service.is_stale() => service.clone(),
+                _ => match Service::load(path.clone(), runtime, config.compile_cache_dir(), true) {
+                    Ok(service) => Arc::new(service),
+                    Err(e) if config.strict => {
+                        return Err(anyhow!("Error loading service in {}: {e:#}", path.display()));
+                    }
+                    Err(e) => {
+                        let kept = if loaded.is_some() { ", keeping previous" } else { "" };
+                        tracing::error!("Error loading service in {}{kept}: {e:#}", path.display());
+                        load_errors.push(LoadError {
+                            directory: path.clone(),
+                            error: format!("{e:#}"),
+                        });
+                        // One bad write should not take a working service
+                        // offline
+                        match loaded {
+                            Some(service) => service.clone(),
+                            None => continue,
+                        }
+                    }
+                },
+            };
+
+            // Routes refer to services by name, so a duplicate would make
+            // them ambiguous
+            if let Some(existing) = services.get(&service.name) {
+                return Err(anyhow!(
+                    "Services in {} and {} are both named {}",
+                    existing.directory.display(),
+                    path.display(),
+                    service.name
+                ));
+            }
+            services.insert(service.name.clone(), service);
+        }
+
+        if let Some(cache) = config.compile_cache_dir() {
+            let entries = services.values().filter_map(|service| service.cache_entry.as_deref());
+            compile::prune_cache(cache, entries);
+        }
+
+        let mut missing_services = config
+            .routed_services()
+            .filter(|name| !services.contains_key(*name))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        missing_services.sort();
+        missing_services.dedup();
+        if !missing_services.is_empty() {
+            let message = format!(
+                "Routes refer to services that are not loaded: {}",
+                missing_services.join(", ")
+            );
+            if config.strict {
+                return Err(anyhow!(message));
+            }
+            tracing::error!("{message}");
+        }
+
+        Ok(WasmState {
+            config,
+            services,
+            error_page,
+            load_errors,
+            missing_services,
+        })
+    }
+}
+
+/// Directories to load services from: those listed in `services`, with glob
+/// patterns expanded, or else every directory next to the configuration file
+/// that contains a `service.toml`.
+pub fn service_dirs(config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    match &config.services {
+        Some(entries) => {
+            for entry in entries {
+                // Listed directories are kept even if they are not services,
+                // so that loading them reports the problem
+                if glob::Pattern::escape(entry) == *entry {
+                    dirs.push(PathBuf::from(entry).clean());
+                    continue;
+                }
+                let matches = glob::glob(entry)
+                    .map_err(|e| anyhow!("Invalid service pattern {entry:?}: {e}"))?;
+                for path in matches {
+                    let path = path?;
+                    if path.is_dir() && path.join("service.toml").exists() {
+                        dirs.push(path.clean());
+                    }
+                }
+            }
+        }
+        None => {
+            for entry in std::fs::read_dir(".")? {
+                let path = entry?.path();
+                if path.is_dir() && path.join("service.toml").exists() {
+                    dirs.push(path);
+                } else {
+                    let path = path.display();
+                    tracing::debug!("Skipping {path}, which is not a service directory");
+                }
+            }
+        }
+    }
+    dirs.sort();
+    dirs.dedup();
+    Ok(dirs)
+}
+
+/// The current `WasmState`, which can be swapped out atomically, along with
+/// process-wide state. Requests take a snapshot when they start, so in-flight
+/// requests finish on the version they were routed with.
+pub struct SharedState {
+    current: RwLock<Arc<WasmState>>,
+    pub config_path: PathBuf,
+    pub runtime: Arc<Runtime>,
+    /// Wakes the reloader, for changes made through the admin API
+    pub reload: Notify,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    /// Opened at startup; changes to `access_log` take effect on restart
+    pub access_log: Option<AccessLog>,
+}
+
+impl SharedState {
+    pub fn new(
+        config_path: PathBuf,
+        runtime: Arc<Runtime>,
+        state: WasmState,
+    ) -> anyhow::Result<Self> {
+        let access_log = state.config.access_log.as_ref().map(AccessLog::open).transpose()?;
+        Ok(Self {
+            current: RwLock::new(Arc::new(state)),
+            config_path,
+            runtime,
+            reload: Notify::new(),
+            shutdown: Shutdown::default(),
+            metrics: Metrics::default(),
+            access_log,
+        })
+    }
+
+    pub fn current(&self) -> Arc<WasmState> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, state: WasmState) {
+        *self.current.write().unwrap() = Arc::new(state);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use hyper::server::accept::Accept;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::state::SharedState;
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connections whose handshake has completed but which hyper has not yet
+/// accepted.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS termination, as declared in `config.toml`:
+///
+/// ```toml
+/// [tls]
+/// listen = ["0.0.0.0:443"]
+///
+/// [[tls.certificates]]
+/// cert = "certs/example.com.pem"
+/// key = "certs/example.com.key"
+/// names = ["example.com", "*.example.com"]
+/// ```
+///
+/// The certificate is chosen by the client's SNI server name. The first
+/// certificate is used when no other one matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// Addresses to accept HTTPS connections on
+    pub listen: Vec<SocketAddr>,
+    pub certificates: Vec<CertificateConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateConfig {
+    /// PEM file containing the certificate chain
+    pub cert: PathBuf,
+    /// PEM file containing the private key
+    pub key: PathBuf,
+    /// Server names this certificate is selected for. A leading `*.` matches
+    /// any single subdomain.
+    #[serde(default)]
+    pub names: Vec<String>,
+}
+
+impl CertificateConfig {
+    fn load(&self) -> anyhow::Result<Arc<CertifiedKey>> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))?
+            .into_iter()
+            .map(Certificate)
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return Err(anyhow!("No certificates found in {}", self.cert.display()));
+        }
+
+        let key = load_private_key(&self.key)?;
+        let key = sign::any_supported_type(&key)
+            .map_err(|_| anyhow!("Unsupported private key type in {}", self.key.display()))?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+
+    /// The latest modification time of the certificate and key files.
+    fn modified(&self) -> Option<SystemTime> {
+        let cert = std::fs::metadata(&self.cert).and_then(|m| m.modified()).ok()?;
+        let key = std::fs::metadata(&self.key).and_then(|m| m.modified()).ok()?;
+        Some(cert.max(key))
+    }
+}
+
+fn load_private_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(anyhow!("No private key found in {}", path.display()))
+}
+
+/// Selects a certificate by SNI server name. The loaded certificates can be
+/// swapped out while connections are being served.
+pub struct CertResolver {
+    certificates: Vec<CertificateConfig>,
+    /// Lowercased server name -> index into `certificates`
+    names: HashMap<String, usize>,
+    loaded: RwLock<Vec<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new(certificates: Vec<CertificateConfig>) -> anyhow::Result<Self> {
+        if certificates.is_empty() {
+            return Err(anyhow!("TLS is enabled but no certificates are configured"));
+        }
+        let loaded = certificates
+            .iter()
+            .map(|cert| {
+                cert.load()
+                    .map_err(|e| anyhow!("Error loading {}: {e}", cert.cert.display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut names = HashMap::new();
+        for (index, cert) in certificates.iter().enumerate() {
+            for name in &cert.names {
+                names.entry(name.to_ascii_lowercase()).or_insert(index);
+            }
+        }
+        Ok(Self {
+            certificates,
+            names,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn index_for(&self, server_name: &str) -> usize {
+        let server_name = server_name.to_ascii_lowercase();
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+        self.names
+            .get(&server_name)
+            .or_else(|| wildcard.and_then(|wildcard| self.names.get(&wildcard)))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Poll the certificate files for changes, reloading any certificate
+    /// whose files were modified. A certificate that fails to load keeps
+    /// serving its previous version.
+    pub fn spawn_reloader(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut modified = self
+                .certificates
+                .iter()
+                .map(CertificateConfig::modified)
+                .collect::<Vec<_>>();
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                for (index, cert) in self.certificates.iter().enumerate() {
+                    let current = cert.modified();
+                    if current == modified[index] {
+                        continue;
+                    }
+                    match cert.load() {
+                        Ok(key) => {
+                            self.loaded.write().unwrap()[index] = key;
+                            modified[index] = current;
+                            tracing::info!("Reloaded TLS certificate {}", cert.cert.display());
+                        }
+                        Err(e) => tracing::error!(
+                            "Error reloading TLS certificate {}: {e}",
+                            cert.cert.display()
+                        ),
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let index = client_hello
+            .server_name()
+            .map(|name| self.index_for(name))
+            .unwrap_or(0);
+        self.loaded.read().unwrap().get(index).cloned()
+    }
+}
+
+/// Build a TLS acceptor whose certificates are reloaded when they change on
+/// disk.
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let resolver = Arc::new(CertResolver::new(config.certificates.clone())?);
+    resolver.clone().spawn_reloader();
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Accepts TCP connections and performs TLS handshakes in the background, so
+/// that a slow client cannot hold up other connections.
+pub struct TlsIncoming {
+    connections: mpsc::Receiver<TlsStream<TcpStream>>,
+}
+
+impl TlsIncoming {
+    /// Listen on `addr` until the host starts draining.
+    pub async fn bind(
+        addr: SocketAddr,
+        acceptor: TlsAcceptor,
+        state: Arc<SharedState>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (sender, connections) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = state.shutdown.draining() => break,
+                };
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // Usually transient (e.g. out of file descriptors)
+                        tracing::warn!("Error accepting connection on {addr}: {e}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let handshake = acceptor.accept(stream);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send(stream).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake failed on {addr}: {e}"),
+                        Err(_) => tracing::debug!("TLS handshake timed out on {addr}"),
+                    }
+                });
+            }
+        });
+        Ok(Self { connections })
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<TcpStream>;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections.poll_recv(cx).map(|stream| stream.map(Ok))
+    }
+}
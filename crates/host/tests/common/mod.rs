@@ -0,0 +1,141 @@
+//! Runs the host binary against a temporary configuration, for tests that
+//! exercise it over HTTP.
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How long the host may take to compile its services and start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The sample service in `apogee/guest`.
+pub fn sample_service() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../apogee/guest")
+}
+
+/// The service built from `crates/test-guest` by `scripts/build-guests.sh`.
+pub fn test_service() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/services/test-guest")
+}
+
+/// Routes every request to the test service.
+pub const TEST_ROUTES: &str = "[routes.\"/\"]\nname = \"test\"";
+
+/// A running host, killed when dropped.
+pub struct Host {
+    process: Child,
+    pub addr: SocketAddr,
+    pub admin_addr: SocketAddr,
+    _dir: TempDir,
+}
+
+impl Host {
+    /// Start the host with `services` and the given route tables, and wait
+    /// until every service is loaded.
+    pub async fn start(services: &[PathBuf], routes: &str) -> Host {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = free_addr();
+        let admin_addr = free_addr();
+        let services = services
+            .iter()
+            .map(|service| format!("{:?}", service.canonicalize().unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = format!(
+            "listen = [\"{addr}\"]\n\
+             services = [{services}]\n\
+             strict = true\n\
+             \n\
+             [admin]\n\
+             listen = \"{admin_addr}\"\n\
+             \n\
+             {routes}\n"
+        );
+        let config_path = dir.path().join("config.toml");
+        std::fs::write(&config_path, config).unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_apogee-host"))
+            .arg("--config")
+            .arg(&config_path)
+            .env("RUST_LOG", "warn")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut host = Host {
+            process,
+            addr,
+            admin_addr,
+            _dir: dir,
+        };
+        host.wait_until_ready().await;
+        host
+    }
+
+    async fn wait_until_ready(&mut self) {
+        let started = Instant::now();
+        let health = format!("http://{}/health", self.admin_addr);
+        loop {
+            if let Some(status) = self.process.try_wait().unwrap() {
+                panic!(
+                    "The host exited with {status} before becoming ready. If a service failed \
+                     to load, rebuild the components with scripts/build-guests.sh"
+                );
+            }
+            let ready = Client::new().get(health.parse().unwrap()).await;
+            if matches!(ready, Ok(res) if res.status() == StatusCode::OK) {
+                return;
+            }
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "The host did not become ready");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    pub async fn get(&self, path: &str) -> Response<Body> {
+        Client::new().get(self.url(path).parse().unwrap()).await.unwrap()
+    }
+
+    pub async fn request(&self, req: Request<Body>) -> Response<Body> {
+        Client::new().request(req).await.unwrap()
+    }
+
+    /// Send `request` as written, for requests an HTTP client would refuse to
+    /// send, and return the status line of the response.
+    pub async fn raw_request(&self, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+            .await
+            .expect("The host did not close the connection")
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        response.lines().next().unwrap_or_default().to_string()
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// An address that nothing is listening on yet.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+pub async fn body_bytes(res: Response<Body>) -> Vec<u8> {
+    hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()
+}
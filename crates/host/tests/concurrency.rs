@@ -0,0 +1,23 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{test_service, Host, TEST_ROUTES};
+
+#[tokio::test]
+async fn slow_requests_do_not_serialize() {
+    let host = Host::start(&[test_service()], TEST_ROUTES).await;
+    assert_eq!(host.get("/spin?ms=0").await.status(), 200);
+
+    // Each guest keeps busy for 500ms, so the pair takes about a second if
+    // one waits for the other
+    let started = Instant::now();
+    let (a, b) = tokio::join!(host.get("/spin?ms=500"), host.get("/spin?ms=500"));
+    let elapsed = started.elapsed();
+    assert_eq!(a.status(), 200);
+    assert_eq!(b.status(), 200);
+    assert!(
+        elapsed < Duration::from_millis(900),
+        "Two 500ms requests took {elapsed:?}"
+    );
+}
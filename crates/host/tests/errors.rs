@@ -0,0 +1,100 @@
+mod common;
+
+use hyper::{Body, Method, Request, StatusCode};
+
+use common::{body_bytes, test_service, Host, TEST_ROUTES};
+
+/// The test service's `max_request_body_bytes`.
+const BODY_LIMIT: usize = 1024;
+
+async fn start() -> Host {
+    Host::start(&[test_service()], TEST_ROUTES).await
+}
+
+fn post(host: &Host, path: &str, body: Body) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(host.url(path))
+        .body(body)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn body_within_limit_is_echoed() {
+    let host = start().await;
+    let res = host.request(post(&host, "/echo", Body::from("hello"))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, b"hello");
+}
+
+#[tokio::test]
+async fn malformed_body_is_bad_request() {
+    let host = start().await;
+    let status = host
+        .raw_request(
+            b"POST /echo HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\
+              Connection: close\r\n\r\nzz\r\nhello\r\n0\r\n\r\n",
+        )
+        .await;
+    assert!(status.contains(" 400 "), "{status}");
+}
+
+#[tokio::test]
+async fn announced_length_over_limit_is_too_large() {
+    let host = start().await;
+    let body = Body::from(vec![b'a'; BODY_LIMIT + 1]);
+    let res = host.request(post(&host, "/echo", body)).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn streamed_body_over_limit_is_too_large() {
+    let host = start().await;
+    // Without a length, the limit is only hit while the guest reads
+    let chunks = vec![Ok::<_, std::io::Error>(vec![b'a'; BODY_LIMIT / 2]); 3];
+    let body = Body::wrap_stream(futures::stream::iter(chunks));
+    let res = host.request(post(&host, "/echo", body)).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn instantiation_failure_is_internal_error() {
+    // A service whose preopened directory disappears after it was loaded
+    // can no longer be instantiated
+    let dir = tempfile::tempdir().unwrap();
+    let wasm = test_service().join("test_guest.component.wasm");
+    let service = format!(
+        "name = \"test\"\n\
+         wasm = {:?}\n\
+         filesystem = [{{ path = \"data\", target = \"/data\" }}]\n",
+        wasm.canonicalize().unwrap()
+    );
+    std::fs::write(dir.path().join("service.toml"), service).unwrap();
+    std::fs::create_dir(dir.path().join("data")).unwrap();
+    let host = Host::start(&[dir.path().to_path_buf()], TEST_ROUTES).await;
+
+    std::fs::remove_dir(dir.path().join("data")).unwrap();
+    let res = host.get("/echo").await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn trap_is_internal_error() {
+    let host = start().await;
+    let res = host.get("/trap").await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn guest_error_is_internal_error() {
+    let host = start().await;
+    let res = host.get("/error").await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn invalid_response_header_is_bad_gateway() {
+    let host = start().await;
+    let res = host.get("/invalid-header").await;
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use hyper::StatusCode;
+
+use common::Host;
+
+/// The test guest, with `[limits]` set by the service in
+/// `tests/services/<name>`.
+fn limited_service(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/services").join(name)
+}
+
+async fn start(name: &str) -> Host {
+    let routes = format!("[routes.\"/\"]\nname = \"{name}\"");
+    Host::start(&[limited_service(name)], &routes).await
+}
+
+#[tokio::test]
+async fn deadline_is_gateway_timeout() {
+    // The service allows 200ms
+    let host = start("timeout").await;
+    assert_eq!(host.get("/spin?ms=0").await.status(), StatusCode::OK);
+    let res = host.get("/spin?ms=2000").await;
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn fuel_exhaustion_is_service_unavailable() {
+    // The service allows a million units of fuel, which a busy loop burns
+    // through well before its 5s deadline
+    let host = start("fuel").await;
+    assert_eq!(host.get("/spin?ms=0").await.status(), StatusCode::OK);
+    let res = host.get("/spin?ms=4000").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,17 @@
+mod common;
+
+use common::{body_bytes, sample_service, Host};
+
+const ROUTES: &str = "[routes.\"/\"]\nname = \"guest\"";
+
+#[tokio::test]
+async fn binary_files_are_served_unchanged() {
+    let host = Host::start(&[sample_service()], ROUTES).await;
+
+    let res = host.get("/logo.png").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["content-type"], "image/png");
+    let expected = std::fs::read(sample_service().join("data/logo.png")).unwrap();
+    let body = body_bytes(res).await;
+    assert!(body == expected, "logo.png was served with different contents");
+}
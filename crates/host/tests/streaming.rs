@@ -0,0 +1,42 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{body_bytes, test_service, Host, TEST_ROUTES};
+
+/// The size of the chunks the test guest streams.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[tokio::test]
+async fn large_streamed_body_arrives_intact() {
+    let host = Host::start(&[test_service()], TEST_ROUTES).await;
+    let res = host.get("/stream?chunks=64").await;
+    assert_eq!(res.status(), 200);
+    let body = body_bytes(res).await;
+    assert_eq!(body.len(), 64 * CHUNK_SIZE);
+    for (i, chunk) in body.chunks(CHUNK_SIZE).enumerate() {
+        assert!(chunk.iter().all(|&b| b == i as u8), "chunk {i} was altered");
+    }
+}
+
+#[tokio::test]
+async fn head_is_sent_before_the_guest_returns() {
+    let host = Host::start(&[test_service()], TEST_ROUTES).await;
+    assert_eq!(host.get("/spin?ms=0").await.status(), 200);
+
+    // The guest keeps busy for a second after sending the head and a chunk
+    let started = Instant::now();
+    let res = host.get("/stream?chunks=1&ms=1000").await;
+    let head = started.elapsed();
+    assert_eq!(res.status(), 200);
+    assert!(head < Duration::from_millis(800), "The head took {head:?}");
+    assert_eq!(body_bytes(res).await.len(), CHUNK_SIZE);
+}
+
+#[tokio::test]
+async fn trap_after_head_aborts_the_body() {
+    let host = Host::start(&[test_service()], TEST_ROUTES).await;
+    let res = host.get("/stream?chunks=1&trap=1").await;
+    assert_eq!(res.status(), 200);
+    assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+}
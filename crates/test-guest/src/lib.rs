@@ -0,0 +1,93 @@
+//! A guest for the host's integration tests, which behaves as selected by
+//! the request path.
+use std::time::{Duration, Instant};
+
+use apogee_sdk::entrypoint;
+use apogee_sdk::http::{body, Header, Request, Response, Version};
+
+/// Size of the chunks written by the `stream` path.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[entrypoint(http)]
+pub fn handle_http_request(req: Request) -> Result<Response, String> {
+    match req.context.path.trim_start_matches('/') {
+        // Keep busy for `ms` milliseconds without yielding to the host
+        "spin" => {
+            spin(number_param(&req, "ms"));
+            respond(req.version, 200, b"")
+        }
+        // Send the head, then `chunks` chunks of `STREAM_CHUNK_SIZE` bytes,
+        // then keep busy for `ms` milliseconds before returning, or trap if
+        // `trap` is given
+        "stream" => {
+            let body = body::new_outgoing();
+            body::stream(200, &[], body)?;
+            for i in 0..number_param(&req, "chunks") {
+                body::write(body, &[i as u8; STREAM_CHUNK_SIZE])?;
+            }
+            spin(number_param(&req, "ms"));
+            if query_param(&req, "trap").is_some() {
+                trap();
+            }
+            respond(req.version, 200, b"")
+        }
+        // Send the request body back
+        "echo" => {
+            let contents = body::read_to_end(req.body)?;
+            respond(req.version, 200, &contents)
+        }
+        "trap" => trap(),
+        "error" => Err("Failing as requested".to_string()),
+        // Respond with a header name the host cannot accept
+        "invalid-header" => Ok(Response {
+            status: 200,
+            version: req.version,
+            headers: vec![Header {
+                key: b"not a header".to_vec(),
+                value: b"value".to_vec(),
+            }],
+            body: body::from_bytes(b"")?,
+        }),
+        path => Err(format!("Unknown test path {path:?}")),
+    }
+}
+
+/// Trap without going through the panic handler, which would first try to
+/// print a message.
+fn trap() -> ! {
+    #[cfg(target_arch = "wasm32")]
+    core::arch::wasm32::unreachable();
+    #[cfg(not(target_arch = "wasm32"))]
+    unreachable!("Only traps when compiled to WebAssembly");
+}
+
+/// Keep busy for `ms` milliseconds without yielding to the host.
+fn spin(ms: u64) {
+    let deadline = Instant::now() + Duration::from_millis(ms);
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+fn number_param(req: &Request, name: &str) -> u64 {
+    query_param(req, name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+fn query_param<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.context
+        .query
+        .as_deref()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn respond(version: Version, status: u16, contents: &[u8]) -> Result<Response, String> {
+    Ok(Response {
+        status,
+        version,
+        headers: vec![],
+        body: body::from_bytes(contents)?,
+    })
+}
@@ -31,6 +31,7 @@ pub struct WasiCtx {
     default_monotonic: wasi_clocks::MonotonicClock,
     default_wall: wasi_clocks::WallClock,
     logging_context: String,
+    logging_span: tracing::Span,
 }
 
 impl Default for WasiCtx {
@@ -45,6 +46,18 @@ impl Default for WasiCtx {
             default_monotonic,
             default_wall,
             logging_context: "I/O".to_string(),
+            logging_span: tracing::Span::none(),
         }
     }
-}
\ No newline at end of file
+}
+impl WasiCtx {
+    /// The resource table backing this context, shared with host interfaces
+    /// layered on top of WASI.
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut Table {
+        &mut self.table
+    }
+}
@@ -1,13 +1,28 @@
+use tracing::Span;
+
 use crate::{wasi_logging, WasiCtx};
 
 impl wasi_logging::WasiLogging for WasiCtx {
     fn log(
         &mut self,
-        _level: wasi_logging::Level,
-        _context: String,
+        level: wasi_logging::Level,
+        context: String,
         message: String,
     ) -> anyhow::Result<()> {
-        print!("[{}] {message}", self.logging_context);
+        use wasi_logging::Level;
+
+        // Emit the event inside the span of the request being handled, so it
+        // carries the request's ID and service
+        let _entered = self.logging_span.enter();
+        let source = self.logging_context.as_str();
+        let message = message.trim_end();
+        match level {
+            Level::Trace => tracing::trace!(target: "guest", %source, %context, "{message}"),
+            Level::Debug => tracing::debug!(target: "guest", %source, %context, "{message}"),
+            Level::Info => tracing::info!(target: "guest", %source, %context, "{message}"),
+            Level::Warn => tracing::warn!(target: "guest", %source, %context, "{message}"),
+            Level::Error => tracing::error!(target: "guest", %source, %context, "{message}"),
+        }
         Ok(())
     }
 }
@@ -16,4 +31,10 @@ impl WasiCtx {
     pub fn set_context(&mut self, context: String) {
         self.logging_context = context;
     }
-}
\ No newline at end of file
+
+    /// Attach guest log events to `span`, typically the span of the request
+    /// being handled.
+    pub fn set_span(&mut self, span: Span) {
+        self.logging_span = span;
+    }
+}
@@ -0,0 +1,26 @@
+#!/bin/sh
+# Build the guest crates and wrap them into components, as loaded by the host.
+#
+# Requires the `wasm32-wasi` Rust target and `wasm-tools`:
+#
+#   rustup target add wasm32-wasi
+#   cargo install wasm-tools
+#
+# The components must be rebuilt whenever the WIT files in
+# `crates/bindings/wit` change, or the host will reject them at load.
+set -eu
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+cd "$root"
+
+# component <crate> <output>
+component() {
+    cargo build --release --target wasm32-wasi -p "$1"
+    wasm-tools component new "target/wasm32-wasi/release/$(echo "$1" | tr - _).wasm" \
+        --adapt adapters/wasi_snapshot_preview1.wasm \
+        -o "$2"
+    echo "Built $2"
+}
+
+component guest apogee/guest/guest.component.wasm
+component test-guest crates/host/tests/services/test-guest/test_guest.component.wasm